@@ -1,6 +1,8 @@
 // Reference from: https://stackoverflow.com/questions/65156743/what-target-features-uses-rustc-by-default
 // And : https://gist.github.com/AngelicosPhosphoros/4f8c9f08656e0812f4ed3560e53bd600
 #![recursion_limit = "512"]
+// feature names come from `rustc --print target-features` of every target, not only the host
+#![allow(unexpected_cfgs)]
 
 // This script prints all cpu features which active in this build.
 // There are 3 steps in usage of script:
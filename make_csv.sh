@@ -3,7 +3,7 @@
 output_file="output.csv"
 
 # Initialize the output file with header
-echo '"filename","Run R0S","Run R1S","Run R2S","Run R2M (0,0)","Run R2M (0,1)","Run R2M (0,4)","Run R3S","Run R3M (0,0)","Run R3M (0,1)","Run R3M (0,4)","filesize"' > $output_file
+echo '"filename","Run R0S","Run R1S","Run R2S","Run R2M (0,0)","Run R2M (0,1)","Run R2M (0,4)","Run R3S","Run R3M (0,0)","Run R3M (0,1)","Run R3M (0,4)","Run R2B (0,1)","Run R3B (0,1)","Run R4M (0,1)","Run R5M (0,1)","filesize"' > $output_file
 
 # Loop through each input file
 for input_file in bench*.txt; do
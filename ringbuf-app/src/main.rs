@@ -1,15 +1,17 @@
-mod helper;
-mod r0;
-mod r1;
-mod r2;
-#[macro_use]
-mod r3;
-
-use std::{fmt::Display, str::FromStr, thread::spawn, vec};
-
-use helper::{RingBufConsumer, RingBufProducer, RingBufTrait};
-pub use r0::RingBuf as RingBuf0;
-pub use r1::RingBuf as RingBuf1;
+use std::{
+    fmt::Display,
+    mem::MaybeUninit,
+    str::FromStr,
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use ringbuf::{r0::RingBuf as RingBuf0, r1::RingBuf as RingBuf1, r2, r3, r4, r5, wait};
+use ringbuf::{
+    Backing, RingBufBatchConsumer, RingBufBatchProducer, RingBufBlockingConsumer,
+    RingBufBlockingProducer, RingBufConsumer, RingBufProducer, RingBufTrait, TryDequeueError,
+    WaitStrategy,
+};
 use structopt::{clap::arg_enum, StructOpt};
 
 #[derive(Debug, Clone, Copy)]
@@ -81,6 +83,27 @@ struct Opt {
     ringbuf: RingBufType,
     #[structopt(short, long)]
     cores: Option<CorePair>,
+    /// number of items per enqueue_slice/dequeue_into call in R2B/R3B
+    #[structopt(long, default_value = "64")]
+    batch_size: usize,
+    /// use blocking enqueue/dequeue with this wait strategy in R2M/R3M
+    #[structopt(short, long, possible_values = &WaitType::variants(), case_insensitive = true)]
+    wait: Option<WaitType>,
+    /// number of producer threads in R4M/R5M
+    #[structopt(long, default_value = "2")]
+    producers: usize,
+    /// number of consumer threads in R5M
+    #[structopt(long, default_value = "2")]
+    consumers: usize,
+    /// cores for the threads of R4M/R5M, e.g. "0,1,2,3". Producers take the first ones
+    #[structopt(long, use_delimiter = true)]
+    core_list: Vec<usize>,
+    /// storage of the R2*/R3* buffers. Huge falls back to transparent huge pages, then the heap
+    #[structopt(long, possible_values = &BackingType::variants(), case_insensitive = true)]
+    backing: Option<BackingType>,
+    /// publish the indices of R3S/R3M/R3L every N items instead of on each one
+    #[structopt(long, default_value = "1")]
+    publish_interval: usize,
 }
 
 arg_enum! {
@@ -92,9 +115,47 @@ arg_enum! {
         R2M,
         R3S,
         R3M,
+        R2B,
+        R3B,
+        R3L,
+        R4M,
+        R5M,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    enum WaitType {
+        Spin,
+        SpinHint,
+        Yield,
+        Backoff,
+        Park,
+        Futex,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    enum BackingType {
+        Heap,
+        Huge,
+        Mirror,
     }
 }
 
+/// CPU time consumed by all threads of this process
+fn process_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
 fn bench_single_thread<R: RingBufTrait<i32>>(rb: &mut R, opt: &Opt) -> String {
     let start = std::time::Instant::now();
     for _ in 0..opt.loop_count {
@@ -143,11 +204,7 @@ fn bench_multi_thread_pc<
     let CorePair {
         producer: core_p,
         consumer: core_c,
-    }: CorePair = if opt.cores.is_some() {
-        opt.cores.unwrap()
-    } else {
-        CorePair::default()
-    };
+    }: CorePair = opt.cores.unwrap_or_default();
 
     let start = std::time::Instant::now();
     let loop_count = opt.loop_count;
@@ -188,50 +245,456 @@ fn bench_multi_thread_pc<
     format!("{} ops in {:5} ms  {:9} ops/ms", count, ms, count / ms)
 }
 
+/// Throughput and the time from enqueue to dequeue of each item.
+/// The items are the enqueue time, latencies are counted in power of two buckets.
+fn bench_latency<
+    P: RingBufProducer<u64> + Send + 'static,
+    C: RingBufConsumer<u64> + Send + 'static,
+>(
+    p: P,
+    c: C,
+    opt: &Opt,
+) -> String {
+    let CorePair {
+        producer: core_p,
+        consumer: core_c,
+    }: CorePair = opt.cores.unwrap_or_default();
+
+    let origin = Instant::now();
+    let total = opt.loop_count * opt.enqueue_count;
+    let h_p = spawn(move || {
+        if !core_affinity::set_for_current(core_p) {
+            println!("set_for_current failed");
+        }
+        for _ in 0..total {
+            while !p.enqueue(origin.elapsed().as_nanos() as u64) {}
+        }
+    });
+    let h_c = spawn(move || {
+        if !core_affinity::set_for_current(core_c) {
+            println!("set_for_current failed");
+        }
+        // bucket iは[2^(i-1), 2^i) nsを数える
+        let mut hist = [0_usize; 65];
+        let mut sum = 0_u64;
+        let mut max = 0_u64;
+        let mut count = total;
+        while 0 < count {
+            if let Some(sent) = c.dequeue() {
+                let latency = origin.elapsed().as_nanos() as u64 - sent;
+                hist[(u64::BITS - latency.leading_zeros()) as usize] += 1;
+                sum += latency;
+                max = max.max(latency);
+                count -= 1;
+            }
+        }
+        (hist, sum, max)
+    });
+    h_p.join().unwrap();
+    let (hist, sum, max) = h_c.join().unwrap();
+    let ms = origin.elapsed().as_millis() as usize;
+    let percentile = |q: f64| {
+        let mut seen = 0;
+        for (i, n) in hist.iter().enumerate() {
+            seen += n;
+            if (total as f64 * q) <= seen as f64 {
+                return 1_u64 << i;
+            }
+        }
+        max
+    };
+    let count = total * 2;
+    format!(
+        "{} ops in {:5} ms  {:9} ops/ms  latency avg {} ns p50 <{} ns p99 <{} ns max {} ns",
+        count,
+        ms,
+        count / ms.max(1),
+        sum / total.max(1) as u64,
+        percentile(0.5),
+        percentile(0.99),
+        max
+    )
+}
+
+/// Cores for each producer and consumer thread.
+/// `--core-list` is assigned round robin, producers first, otherwise the `--cores` pair is used.
+fn thread_cores(
+    opt: &Opt,
+    producers: usize,
+    consumers: usize,
+) -> (Vec<core_affinity::CoreId>, Vec<core_affinity::CoreId>) {
+    if opt.core_list.is_empty() {
+        let CorePair { producer, consumer } = opt.cores.unwrap_or_default();
+        return (vec![producer; producers], vec![consumer; consumers]);
+    }
+    let cores = (0..producers + consumers)
+        .map(|i| core_affinity::CoreId {
+            id: opt.core_list[i % opt.core_list.len()],
+        })
+        .collect::<Vec<_>>();
+    (cores[..producers].to_vec(), cores[producers..].to_vec())
+}
+
+fn spawn_producers<P: RingBufProducer<i32> + Clone + Send + 'static>(
+    p: P,
+    cores: Vec<core_affinity::CoreId>,
+    opt: &Opt,
+) -> Vec<JoinHandle<()>> {
+    let loop_count = opt.loop_count;
+    let enqueue_count = opt.enqueue_count;
+    cores
+        .into_iter()
+        .map(|core| {
+            let p = p.clone();
+            spawn(move || {
+                if !core_affinity::set_for_current(core) {
+                    println!("set_for_current failed");
+                }
+                for _ in 0..loop_count {
+                    let mut count = enqueue_count;
+                    while 0 < count {
+                        if p.enqueue(count as i32) {
+                            count -= 1;
+                        }
+                    }
+                }
+            })
+        })
+        .collect()
+}
+
+fn bench_multi_producer<
+    P: RingBufProducer<i32> + Clone + Send + 'static,
+    C: RingBufConsumer<i32> + Send + 'static,
+>(
+    p: P,
+    c: C,
+    opt: &Opt,
+) -> String {
+    let (cores_p, cores_c) = thread_cores(opt, opt.producers, 1);
+    let core_c = cores_c[0];
+
+    let start = std::time::Instant::now();
+    let h_ps = spawn_producers(p, cores_p, opt);
+    let total = opt.loop_count * opt.enqueue_count * opt.producers;
+    let h_c = spawn(move || {
+        if !core_affinity::set_for_current(core_c) {
+            println!("set_for_current failed");
+        }
+        let mut count = total;
+        while 0 < count {
+            if c.dequeue().is_some() {
+                count -= 1;
+            }
+        }
+    });
+    for h_p in h_ps {
+        h_p.join().unwrap();
+    }
+    h_c.join().unwrap();
+    let end = std::time::Instant::now();
+    let ms = (end - start).as_millis() as usize;
+    let count = total * 2;
+    format!("{} ops in {:5} ms  {:9} ops/ms", count, ms, count / ms)
+}
+
+fn bench_multi_producer_consumer<
+    P: RingBufProducer<i32> + Clone + Send + 'static,
+    C: RingBufConsumer<i32> + Clone + Send + 'static,
+>(
+    p: P,
+    c: C,
+    opt: &Opt,
+) -> String {
+    let (cores_p, cores_c) = thread_cores(opt, opt.producers, opt.consumers);
+
+    let start = std::time::Instant::now();
+    let h_ps = spawn_producers(p, cores_p, opt);
+    // 全てのProducerがdropされるとDisconnectedになるので、それまで読み続ける
+    let h_cs = cores_c
+        .into_iter()
+        .map(|core| {
+            let c = c.clone();
+            spawn(move || {
+                if !core_affinity::set_for_current(core) {
+                    println!("set_for_current failed");
+                }
+                let mut count = 0;
+                loop {
+                    match c.try_dequeue() {
+                        Ok(_) => count += 1,
+                        Err(TryDequeueError::Empty) => {}
+                        Err(TryDequeueError::Disconnected) => break count,
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(c);
+    for h_p in h_ps {
+        h_p.join().unwrap();
+    }
+    let received = h_cs.into_iter().map(|h| h.join().unwrap()).sum::<usize>();
+    let end = std::time::Instant::now();
+    let total = opt.loop_count * opt.enqueue_count * opt.producers;
+    assert_eq!(received, total);
+    let ms = (end - start).as_millis() as usize;
+    let count = total * 2;
+    format!("{} ops in {:5} ms  {:9} ops/ms", count, ms, count / ms)
+}
+
+fn bench_multi_thread_blocking<
+    P: RingBufBlockingProducer<i32> + Send + 'static,
+    C: RingBufBlockingConsumer<i32> + Send + 'static,
+>(
+    p: P,
+    c: C,
+    opt: &Opt,
+) -> String {
+    let CorePair {
+        producer: core_p,
+        consumer: core_c,
+    }: CorePair = opt.cores.unwrap_or_default();
+
+    let start = std::time::Instant::now();
+    let start_cpu = process_cpu_time();
+    let loop_count = opt.loop_count;
+    let enqueue_count = opt.enqueue_count;
+    let h_p = spawn(move || {
+        if !core_affinity::set_for_current(core_p) {
+            println!("set_for_current failed");
+        }
+        for _ in 0..loop_count {
+            for i in 0..enqueue_count {
+                p.enqueue_blocking(i as i32).unwrap();
+            }
+        }
+    });
+    let loop_count = opt.loop_count;
+    let enqueue_count = opt.enqueue_count;
+    let h_c = spawn(move || {
+        if !core_affinity::set_for_current(core_c) {
+            println!("set_for_current failed");
+        }
+        for _ in 0..loop_count {
+            for _ in 0..enqueue_count {
+                c.dequeue_blocking().unwrap();
+            }
+        }
+    });
+    h_p.join().unwrap();
+    h_c.join().unwrap();
+    let end = std::time::Instant::now();
+    let cpu_ms = (process_cpu_time() - start_cpu).as_millis() as usize;
+    let ms = (end - start).as_millis() as usize;
+    let count = opt.enqueue_count * opt.loop_count * 2;
+    format!(
+        "{} ops in {:5} ms  {:9} ops/ms  cpu {:5} ms",
+        count,
+        ms,
+        count / ms,
+        cpu_ms
+    )
+}
+
+fn r2_buffer<W: WaitStrategy>(opt: &Opt, wait: W) -> r2::Buffer<i32, W> {
+    let buffer = match opt.backing {
+        None | Some(BackingType::Heap) => {
+            r2::Buffer::with_capacity_and_wait(opt.buffer_capacity, wait)
+        }
+        Some(BackingType::Huge) => {
+            r2::Buffer::huge_with_capacity_and_wait(opt.buffer_capacity, wait)
+        }
+        Some(BackingType::Mirror) => panic!("--backing mirror is supported by R3*"),
+    };
+    print_backing(opt, buffer.backing());
+    buffer
+}
+
+fn r3_buffer<T, W: WaitStrategy>(opt: &Opt, wait: W) -> r3::Buffer<T, W> {
+    let buffer = match opt.backing {
+        None | Some(BackingType::Heap) => {
+            r3::Buffer::with_capacity_and_wait(opt.buffer_capacity, wait)
+        }
+        Some(BackingType::Huge) => {
+            r3::Buffer::huge_with_capacity_and_wait(opt.buffer_capacity, wait)
+        }
+        Some(BackingType::Mirror) => {
+            r3::Buffer::mirrored_with_capacity_and_wait(opt.buffer_capacity, wait)
+        }
+    };
+    print_backing(opt, buffer.backing());
+    buffer
+}
+
+fn r3_handles<T, W: WaitStrategy>(opt: &Opt, wait: W) -> (r3::Producer<T, W>, r3::Consumer<T, W>) {
+    let (mut p, mut c) = r3::from_buffer(r3_buffer(opt, wait));
+    p.set_publish_interval(opt.publish_interval);
+    c.set_publish_interval(opt.publish_interval);
+    (p, c)
+}
+
+/// 要求したものが使えるとは限らないので、実際に得られたものを表示する
+fn print_backing(opt: &Opt, backing: Backing) {
+    if let Some(requested) = opt.backing {
+        println!("Backing {}: {:?}", requested, backing);
+    }
+}
+
+fn bench_wait<W: WaitStrategy + Default + 'static>(opt: &Opt) -> String {
+    match opt.ringbuf {
+        RingBufType::R2M => {
+            let (p, c) = r2::from_buffer(r2_buffer(opt, W::default()));
+            bench_multi_thread_blocking(p, c, opt)
+        }
+        RingBufType::R3M => {
+            let (p, c) = r3_handles(opt, W::default());
+            bench_multi_thread_blocking(p, c, opt)
+        }
+        _ => panic!("--wait is supported by R2M and R3M"),
+    }
+}
+
+fn bench_multi_thread_batch<
+    P: RingBufBatchProducer<i32> + Send + 'static,
+    C: RingBufBatchConsumer<i32> + Send + 'static,
+>(
+    p: P,
+    c: C,
+    opt: &Opt,
+) -> String {
+    let CorePair {
+        producer: core_p,
+        consumer: core_c,
+    }: CorePair = opt.cores.unwrap_or_default();
+
+    let start = std::time::Instant::now();
+    let loop_count = opt.loop_count;
+    let enqueue_count = opt.enqueue_count;
+    let batch_size = opt.batch_size;
+    let h_p = spawn(move || {
+        if !core_affinity::set_for_current(core_p) {
+            println!("set_for_current failed");
+        }
+        let items = (0..enqueue_count as i32).collect::<Vec<_>>();
+        for _ in 0..loop_count {
+            let mut count = 0;
+            while count < enqueue_count {
+                let end = enqueue_count.min(count + batch_size);
+                count += p.enqueue_slice(&items[count..end]);
+            }
+        }
+    });
+    let loop_count = opt.loop_count;
+    let enqueue_count = opt.enqueue_count;
+    let batch_size = opt.batch_size;
+    let h_c = spawn(move || {
+        if !core_affinity::set_for_current(core_c) {
+            println!("set_for_current failed");
+        }
+        let mut buf = vec![MaybeUninit::<i32>::uninit(); batch_size];
+        for _ in 0..loop_count {
+            let mut count = enqueue_count;
+            while 0 < count {
+                let len = count.min(batch_size);
+                count -= c.dequeue_into(&mut buf[..len]);
+            }
+        }
+    });
+    h_p.join().unwrap();
+    h_c.join().unwrap();
+    let end = std::time::Instant::now();
+    let ms = (end - start).as_millis() as usize;
+    let count = opt.enqueue_count * opt.loop_count * 2;
+    format!("{} ops in {:5} ms  {:9} ops/ms", count, ms, count / ms)
+}
+
 fn main() {
     let opt = Opt::from_args();
 
-    let result = match opt.ringbuf {
+    let result = match opt.wait {
+        Some(WaitType::Spin) => bench_wait::<wait::BusySpin>(&opt),
+        Some(WaitType::SpinHint) => bench_wait::<wait::SpinLoopHint>(&opt),
+        Some(WaitType::Yield) => bench_wait::<wait::Yield>(&opt),
+        Some(WaitType::Backoff) => bench_wait::<wait::Backoff>(&opt),
+        Some(WaitType::Park) => bench_wait::<wait::Park>(&opt),
+        Some(WaitType::Futex) => bench_wait::<wait::Futex>(&opt),
+        None => bench(&opt),
+    };
+    println!(
+        "Run {}{} {}: {result}",
+        opt.ringbuf,
+        match opt.wait {
+            Some(wait) => format!("/{}", wait),
+            None => "".to_string(),
+        },
+        match opt.cores {
+            _ if !opt.core_list.is_empty() => format!(
+                "({})",
+                opt.core_list
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Some(cores) => format!("{}", cores),
+            None => "     ".to_string(),
+        }
+    );
+}
+
+fn bench(opt: &Opt) -> String {
+    match opt.ringbuf {
         RingBufType::R0S => {
             let mut ringbuf = RingBuf0::<i32>::with_capacity(opt.buffer_capacity);
-            bench_single_thread(&mut ringbuf, &opt)
+            bench_single_thread(&mut ringbuf, opt)
         }
         RingBufType::R1S => {
             let mut ringbuf = RingBuf1::<i32>::with_capacity(opt.buffer_capacity);
-            bench_single_thread(&mut ringbuf, &opt)
+            bench_single_thread(&mut ringbuf, opt)
         }
         RingBufType::R2S => {
-            let (p, c, _) = r2::make::<i32>(opt.buffer_capacity);
-            bench_single_thread_pc(p, c, &opt)
+            let (p, c) = r2::from_buffer(r2_buffer(opt, wait::BusySpin));
+            bench_single_thread_pc(p, c, opt)
         }
         RingBufType::R2M => {
-            let (p, c, _) = r2::make::<i32>(opt.buffer_capacity);
-            bench_multi_thread_pc(p, c, &opt)
+            let (p, c) = r2::from_buffer(r2_buffer(opt, wait::BusySpin));
+            bench_multi_thread_pc(p, c, opt)
         }
         RingBufType::R3S => {
-            let (p, c, _) = r3::make::<i32>(opt.buffer_capacity);
-            bench_single_thread_pc(p, c, &opt)
+            let (p, c) = r3_handles(opt, wait::BusySpin);
+            bench_single_thread_pc(p, c, opt)
         }
         RingBufType::R3M => {
-            let (p, c, _b) = r3::make::<i32>(opt.buffer_capacity);
-            // _b.as_ref().show_cache();
-            bench_multi_thread_pc(p, c, &opt)
+            let (p, c) = r3_handles(opt, wait::BusySpin);
+            bench_multi_thread_pc(p, c, opt)
         }
-    };
-    println!(
-        "Run {} {}: {result}",
-        opt.ringbuf,
-        if opt.cores.is_some() {
-            format!("{}", opt.cores.unwrap())
-        } else {
-            "     ".to_string()
+        RingBufType::R2B => {
+            let (p, c) = r2::from_buffer(r2_buffer(opt, wait::BusySpin));
+            bench_multi_thread_batch(p, c, opt)
         }
-    );
+        RingBufType::R3B => {
+            let (p, c) = r3::from_buffer(r3_buffer(opt, wait::BusySpin));
+            bench_multi_thread_batch(p, c, opt)
+        }
+        RingBufType::R3L => {
+            let (p, c) = r3_handles(opt, wait::BusySpin);
+            bench_latency(p, c, opt)
+        }
+        RingBufType::R4M => {
+            let (p, c) = r4::make::<i32>(opt.buffer_capacity);
+            bench_multi_producer(p, c, opt)
+        }
+        RingBufType::R5M => {
+            let (p, c) = r5::make::<i32>(opt.buffer_capacity);
+            bench_multi_producer_consumer(p, c, opt)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::helper::{RingBufConsumer, RingBufProducer, RingBufTrait};
+    use ringbuf::{RingBufConsumer, RingBufProducer, RingBufTrait};
 
     fn check_ringbuf<R: RingBufTrait<i32>>(mut ringbuf: R) {
         for i in 0..10 {
@@ -252,11 +715,11 @@ mod tests {
     #[test]
     fn test_queue() {
         let cap = 10;
-        check_ringbuf(crate::r0::RingBuf::<i32>::with_capacity(cap));
-        check_ringbuf(crate::r1::RingBuf::<i32>::with_capacity(cap));
-        let (p, c, _) = crate::r2::make::<i32>(cap);
+        check_ringbuf(ringbuf::r0::RingBuf::<i32>::with_capacity(cap));
+        check_ringbuf(ringbuf::r1::RingBuf::<i32>::with_capacity(cap));
+        let (p, c) = ringbuf::r2::make::<i32>(cap);
         check_pc(p, c);
-        let (p, c, _) = crate::r3::make::<i32>(cap);
+        let (p, c) = ringbuf::r3::make::<i32>(cap);
         check_pc(p, c);
     }
 }
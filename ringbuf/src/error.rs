@@ -0,0 +1,270 @@
+use core::{error::Error, fmt};
+
+/// Returned by `try_enqueue` when the ring has no free slot.
+/// The rejected item is handed back so that the caller can retry or spill it.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct Full<T>(pub T);
+
+impl<T> Full<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> fmt::Debug for Full<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Full(..)")
+    }
+}
+
+impl<T> fmt::Display for Full<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ring buffer is full")
+    }
+}
+
+impl<T> Error for Full<T> {}
+
+/// Returned by `try_dequeue` when the ring has no item.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Empty;
+
+impl fmt::Display for Empty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ring buffer is empty")
+    }
+}
+
+impl Error for Empty {}
+
+/// Error of `try_enqueue` on a producer handle.
+/// Both variants carry the rejected item.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum TryEnqueueError<T> {
+    /// The ring has no free slot at the moment.
+    Full(T),
+    /// The consumer side is gone, the item can never be delivered.
+    Disconnected(T),
+}
+
+impl<T> TryEnqueueError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Full(v) | Self::Disconnected(v) => v,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        matches!(self, Self::Full(_))
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, Self::Disconnected(_))
+    }
+}
+
+impl<T> From<Full<T>> for TryEnqueueError<T> {
+    fn from(e: Full<T>) -> Self {
+        Self::Full(e.0)
+    }
+}
+
+impl<T> fmt::Debug for TryEnqueueError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TryEnqueueError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("ring buffer is full"),
+            Self::Disconnected(_) => f.write_str("ring buffer is disconnected"),
+        }
+    }
+}
+
+impl<T> Error for TryEnqueueError<T> {}
+
+/// Error of `try_dequeue` on a consumer handle.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TryDequeueError {
+    /// The ring has no item at the moment.
+    Empty,
+    /// The ring is drained and the producer side is gone.
+    Disconnected,
+}
+
+impl TryDequeueError {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Self::Empty)
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, Self::Disconnected)
+    }
+}
+
+impl From<Empty> for TryDequeueError {
+    fn from(_: Empty) -> Self {
+        Self::Empty
+    }
+}
+
+impl fmt::Display for TryDequeueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("ring buffer is empty"),
+            Self::Disconnected => f.write_str("ring buffer is empty and disconnected"),
+        }
+    }
+}
+
+impl Error for TryDequeueError {}
+
+/// Error of `try_dequeue_lossy` on the consumer of the overwrite ring.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TryDequeueLossyError {
+    /// The ring has no item at the moment.
+    Empty,
+    /// The producer has overwritten this many unread items.
+    /// The consumer has skipped them, the next call returns the oldest remaining item.
+    Lagged(usize),
+    /// The ring is drained and the producer side is gone.
+    Disconnected,
+}
+
+impl TryDequeueLossyError {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Self::Empty)
+    }
+
+    pub fn is_lagged(&self) -> bool {
+        matches!(self, Self::Lagged(_))
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, Self::Disconnected)
+    }
+}
+
+impl From<Empty> for TryDequeueLossyError {
+    fn from(_: Empty) -> Self {
+        Self::Empty
+    }
+}
+
+impl fmt::Display for TryDequeueLossyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("ring buffer is empty"),
+            Self::Lagged(n) => write!(f, "ring buffer overwrote {} unread items", n),
+            Self::Disconnected => f.write_str("ring buffer is empty and disconnected"),
+        }
+    }
+}
+
+impl Error for TryDequeueLossyError {}
+
+/// Returned by blocking operations when the peer is gone.
+/// `enqueue_blocking` hands the item back in it.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct Disconnected<T = ()>(pub T);
+
+impl<T> Disconnected<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> fmt::Debug for Disconnected<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Disconnected(..)")
+    }
+}
+
+impl<T> fmt::Display for Disconnected<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ring buffer is disconnected")
+    }
+}
+
+impl<T> Error for Disconnected<T> {}
+
+/// Error of `enqueue_timeout`. Both variants carry the rejected item.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum EnqueueTimeoutError<T> {
+    /// The ring stayed full until the timeout.
+    Timeout(T),
+    /// The consumer side is gone, the item can never be delivered.
+    Disconnected(T),
+}
+
+impl<T> EnqueueTimeoutError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Timeout(v) | Self::Disconnected(v) => v,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Self::Timeout(_))
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, Self::Disconnected(_))
+    }
+}
+
+impl<T> fmt::Debug for EnqueueTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(_) => f.write_str("Timeout(..)"),
+            Self::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for EnqueueTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(_) => f.write_str("timed out waiting for a free slot"),
+            Self::Disconnected(_) => f.write_str("ring buffer is disconnected"),
+        }
+    }
+}
+
+impl<T> Error for EnqueueTimeoutError<T> {}
+
+/// Error of `dequeue_timeout`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DequeueTimeoutError {
+    /// The ring stayed empty until the timeout.
+    Timeout,
+    /// The ring is drained and the producer side is gone.
+    Disconnected,
+}
+
+impl DequeueTimeoutError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Self::Timeout)
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, Self::Disconnected)
+    }
+}
+
+impl fmt::Display for DequeueTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => f.write_str("timed out waiting for an item"),
+            Self::Disconnected => f.write_str("ring buffer is empty and disconnected"),
+        }
+    }
+}
+
+impl Error for DequeueTimeoutError {}
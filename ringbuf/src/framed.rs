@@ -0,0 +1,254 @@
+//! SPSC ring of variable-length byte records on top of the r3 byte ring.
+//!
+//! Each record is a 4-byte header holding its length followed by the payload, padded to
+//! 4 bytes. A record never wraps at the end of the buffer: when it does not fit before the
+//! end, a padding record fills the rest and the record starts at the head of the buffer.
+
+use std::{mem::MaybeUninit, ops::Deref, slice};
+
+use crate::{
+    error::TryEnqueueError,
+    helper::{RingBufBlockingConsumer, RingBufBlockingProducer},
+    r3,
+};
+
+const HEADER_LEN: usize = 4;
+// ヘッダの最上位bitが立っていればpadding。読み飛ばすだけ
+const PADDING: u32 = 1 << 31;
+
+#[inline]
+fn record_len(len: usize) -> usize {
+    (HEADER_LEN + len + 3) & !3
+}
+
+pub struct Producer {
+    inner: r3::Producer<u8>,
+    // 書き込んだバイト数。ringを作った時点から数えるのでバッファ上の位置が分かる
+    written: usize,
+    capacity: usize,
+}
+
+pub struct Consumer {
+    inner: r3::Consumer<u8>,
+}
+
+/// Creates a ring of `capacity.next_power_of_two()` bytes, at least 16.
+/// A record can be up to [`Producer::max_len`] bytes long.
+pub fn make(capacity: usize) -> (Producer, Consumer) {
+    let capacity = capacity.next_power_of_two().max(16);
+    assert!(capacity <= PADDING as usize, "capacity overflow");
+    let (mut p, c) = r3::make(capacity);
+    // 全体を一度初期化しておけば、以降は初期化済みのバイトしか書かれないので&mut [u8]で渡せる
+    let (first, second) = p.reserve(capacity);
+    first.fill(MaybeUninit::new(0));
+    second.fill(MaybeUninit::new(0));
+    (
+        Producer {
+            inner: p,
+            written: 0,
+            capacity,
+        },
+        Consumer { inner: c },
+    )
+}
+
+impl Producer {
+    /// Longest payload accepted. Half of the ring minus the header, so that a record fits
+    /// after the padding whatever the write position is.
+    pub fn max_len(&self) -> usize {
+        self.capacity / 2 - HEADER_LEN
+    }
+
+    pub fn push(&mut self, record: &[u8]) -> Result<(), TryEnqueueError<()>> {
+        self.push_with(record.len(), |buf| buf.copy_from_slice(record))
+    }
+
+    /// Reserves a record of `len` bytes and lets `f` fill it in place.
+    /// `f` is not called when the ring is full or closed.
+    ///
+    /// # Panics
+    /// Panics when `len` is larger than [`Producer::max_len`].
+    pub fn push_with<F: FnOnce(&mut [u8])>(
+        &mut self,
+        len: usize,
+        f: F,
+    ) -> Result<(), TryEnqueueError<()>> {
+        assert!(len <= self.max_len(), "record is larger than max_len");
+        if self.inner.is_closed() {
+            return Err(TryEnqueueError::Disconnected(()));
+        }
+        let need = record_len(len);
+        let to_end = self.capacity - (self.written & (self.capacity - 1));
+        // 末尾までに収まらなければpaddingで埋めて先頭から書く
+        let pad = if need <= to_end { 0 } else { to_end };
+
+        let (first, second) = self.inner.reserve(pad + need);
+        if first.len() + second.len() < pad + need {
+            return Err(TryEnqueueError::Full(()));
+        }
+        let (first, second) = unsafe { (assume_init(first), assume_init(second)) };
+        if pad != 0 {
+            first[..HEADER_LEN]
+                .copy_from_slice(&(PADDING | (pad - HEADER_LEN) as u32).to_ne_bytes());
+        }
+        // mirroredなら先頭の部分も1つ目の区間に続いている
+        let rec = if pad + need <= first.len() {
+            &mut first[pad..pad + need]
+        } else {
+            &mut second[..need]
+        };
+        rec[..HEADER_LEN].copy_from_slice(&(len as u32).to_ne_bytes());
+        f(&mut rec[HEADER_LEN..HEADER_LEN + len]);
+
+        unsafe { self.inner.commit(pad + need) };
+        self.written = self.written.wrapping_add(pad + need);
+        Ok(())
+    }
+
+    /// Marks the ring as closed, see [`r3::Producer::close`].
+    pub fn close(&self) {
+        self.inner.close();
+    }
+}
+
+impl Consumer {
+    /// Returns the oldest record, which is released when the guard is dropped.
+    pub fn pop(&mut self) -> Option<RecordGuard<'_>> {
+        loop {
+            let (head, _) = self.inner.read_chunk(HEADER_LEN);
+            if head.len() < HEADER_LEN {
+                return None;
+            }
+            let header = u32::from_ne_bytes(head[..HEADER_LEN].try_into().unwrap());
+            if header & PADDING != 0 {
+                self.skip(HEADER_LEN + (header & !PADDING) as usize);
+                continue;
+            }
+
+            let len = header as usize;
+            let total = record_len(len);
+            // レコードは一度にcommitされ、末尾を跨がない
+            let (rec, _) = self.inner.read_chunk(total);
+            debug_assert!(total <= rec.len());
+            let data = rec[HEADER_LEN..].as_ptr();
+            return Some(RecordGuard {
+                consumer: self,
+                data,
+                len,
+                total,
+            });
+        }
+    }
+
+    fn skip(&mut self, n: usize) {
+        self.inner.release(n);
+    }
+
+    /// `true` when the producer has closed the ring.
+    /// Records pushed before the close can still be popped.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// `true` when there is no record to pop.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+/// A record borrowed from the ring. Its bytes are handed back to the producer when dropped.
+pub struct RecordGuard<'a> {
+    consumer: &'a mut Consumer,
+    data: *const u8,
+    len: usize,
+    total: usize,
+}
+
+impl Deref for RecordGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl Drop for RecordGuard<'_> {
+    fn drop(&mut self) {
+        self.consumer.skip(self.total);
+    }
+}
+
+/// # Safety
+/// Every byte of the ring is initialized by [`make`].
+unsafe fn assume_init(s: &mut [MaybeUninit<u8>]) -> &mut [u8] {
+    &mut *(s as *mut [MaybeUninit<u8>] as *mut [u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::TryEnqueueError;
+
+    #[test]
+    fn test_push_pop() {
+        let (mut p, mut c) = super::make(32);
+        assert_eq!(p.max_len(), 12);
+        assert!(c.pop().is_none());
+
+        p.push(b"hello").unwrap();
+        p.push(b"").unwrap();
+        p.push_with(3, |buf| buf.copy_from_slice(b"abc")).unwrap();
+        assert_eq!(&*c.pop().unwrap(), b"hello");
+        assert_eq!(&*c.pop().unwrap(), b"");
+        assert_eq!(&*c.pop().unwrap(), b"abc");
+        assert!(c.pop().is_none());
+
+        // 書き込み位置は24。16byteのレコードは末尾に収まらないのでpaddingが入る
+        p.push(&[7; 12]).unwrap();
+        assert_eq!(&*c.pop().unwrap(), &[7; 12]);
+        p.push(&[1; 12]).unwrap();
+        p.push(&[3; 12]).unwrap();
+        assert_eq!(
+            p.push_with(12, |_| unreachable!()),
+            Err(TryEnqueueError::Full(()))
+        );
+        {
+            let rec = c.pop().unwrap();
+            assert_eq!(&*rec, &[1; 12]);
+            // guardを持っている間はまだ空かない
+            assert_eq!(p.push(&[2; 12]), Err(TryEnqueueError::Full(())));
+        }
+        p.push(&[2; 12]).unwrap();
+        p.close();
+        assert_eq!(p.push(b"x"), Err(TryEnqueueError::Disconnected(())));
+        assert_eq!(&*c.pop().unwrap(), &[3; 12]);
+        assert_eq!(&*c.pop().unwrap(), &[2; 12]);
+        assert!(c.pop().is_none() && c.is_closed());
+    }
+
+    #[test]
+    fn test_multi_thread() {
+        let count = 20_000_usize;
+        let (mut p, mut c) = super::make(256);
+        let h = thread::spawn(move || {
+            for i in 0..count {
+                let len = i % 50;
+                while p.push_with(len, |buf| buf.fill(i as u8)).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+        for i in 0..count {
+            loop {
+                if let Some(rec) = c.pop() {
+                    assert_eq!(rec.len(), i % 50);
+                    assert!(rec.iter().all(|b| *b == i as u8));
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+        h.join().unwrap();
+    }
+}
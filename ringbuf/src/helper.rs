@@ -1,32 +1,365 @@
+use core::{
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+use crate::error::{DequeueTimeoutError, Disconnected, EnqueueTimeoutError};
+use crate::error::{Empty, Full, TryDequeueError, TryEnqueueError};
+
+/// Allocates uninitialized memory for `capacity.next_power_of_two()` elements of `T`.
+///
+/// # Safety
+/// The returned memory is uninitialized and must be released with [`deallocate_buffer`]
+/// using the rounded-up length and [`Backing::Heap`].
+#[cfg(feature = "alloc")]
 pub unsafe fn allocate_buffer<T>(capacity: usize) -> *mut T {
-    let adjusted_size = capacity.next_power_of_two();
-    let layout = std::alloc::Layout::array::<T>(adjusted_size).unwrap();
-    let ptr = std::alloc::alloc(layout);
+    allocate_exact(capacity.next_power_of_two())
+}
+
+/// Allocates uninitialized memory for exactly `len` elements of `T`.
+///
+/// A zero-sized request returns a dangling pointer without allocating.
+///
+/// # Safety
+/// The returned memory is uninitialized and must be released with [`deallocate_buffer`]
+/// using the same `len` and [`Backing::Heap`].
+#[cfg(feature = "alloc")]
+pub unsafe fn allocate_exact<T>(len: usize) -> *mut T {
+    // サイズ0のlayoutをallocに渡すのはUB
+    if core::mem::size_of::<T>() == 0 || len == 0 {
+        return core::ptr::NonNull::dangling().as_ptr();
+    }
+    let layout = alloc::alloc::Layout::array::<T>(len).unwrap();
+    let ptr = alloc::alloc::alloc(layout);
     if ptr.is_null() {
         panic!("failed to allocate memory");
     }
     ptr as *mut T
 }
 
+/// Where the storage of a ring is allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// The global allocator, see [`allocate_buffer`].
+    Heap,
+    /// Explicit huge pages by `mmap(MAP_HUGETLB)`, see [`crate::hugepage`].
+    HugeTlb,
+    /// Anonymous pages with `madvise(MADV_HUGEPAGE)`, see [`crate::hugepage`].
+    TransparentHugePage,
+    /// Pages mapped twice back-to-back, see [`crate::mirror`].
+    Mirrored,
+    /// Memory provided by the caller, see [`crate::storage`].
+    External,
+}
+
+/// Releases `len` elements of `T` allocated with `backing`.
+///
+/// # Safety
+/// `ptr` and `len` must come from the allocator of `backing`,
+/// and the memory must not be used afterwards.
+#[cfg(feature = "alloc")]
+pub unsafe fn deallocate_buffer<T>(ptr: *mut T, len: usize, backing: Backing) {
+    match backing {
+        // allocate_exactが確保していないのでそのまま
+        Backing::Heap if core::mem::size_of::<T>() == 0 || len == 0 => {}
+        Backing::Heap => alloc::alloc::dealloc(
+            ptr as *mut u8,
+            alloc::alloc::Layout::array::<T>(len).unwrap(),
+        ),
+        Backing::HugeTlb | Backing::TransparentHugePage => {
+            crate::hugepage::deallocate_huge(ptr, len)
+        }
+        Backing::Mirrored => crate::mirror::deallocate_mirrored(ptr, len),
+        // 持ち主が解放する
+        Backing::External => {}
+    }
+}
+
 pub trait RingBufTrait<T> {
-    fn enqueue(&mut self, item: T) -> bool;
-    fn dequeue(&mut self) -> Option<T>;
+    fn try_enqueue(&mut self, item: T) -> Result<(), Full<T>>;
+    fn try_dequeue(&mut self) -> Result<T, Empty>;
+
+    /// Same as `try_enqueue` but the item is dropped when the ring is full.
+    #[inline]
+    fn enqueue(&mut self, item: T) -> bool {
+        self.try_enqueue(item).is_ok()
+    }
+
+    #[inline]
+    fn dequeue(&mut self) -> Option<T> {
+        self.try_dequeue().ok()
+    }
 }
 
 pub trait RingBufProducer<T> {
-    fn enqueue(&self, item: T) -> bool;
+    fn try_enqueue(&self, item: T) -> Result<(), TryEnqueueError<T>>;
+
+    /// Same as `try_enqueue` but the item is dropped when it is rejected.
+    #[inline]
+    fn enqueue(&self, item: T) -> bool {
+        self.try_enqueue(item).is_ok()
+    }
 }
 pub trait RingBufConsumer<T> {
-    fn dequeue(&self) -> Option<T>;
+    fn try_dequeue(&self) -> Result<T, TryDequeueError>;
+
+    #[inline]
+    fn dequeue(&self) -> Option<T> {
+        self.try_dequeue().ok()
+    }
+
+    /// Returns an iterator that dequeues items until the ring is empty.
+    /// Items enqueued while iterating are returned as well.
+    fn try_iter(&self) -> TryIter<'_, Self, T>
+    where
+        Self: Sized,
+    {
+        TryIter {
+            consumer: self,
+            _item: PhantomData,
+        }
+    }
+}
+
+/// Iterator returned by [`RingBufConsumer::try_iter`].
+pub struct TryIter<'a, C, T> {
+    consumer: &'a C,
+    _item: PhantomData<fn() -> T>,
 }
 
-// CPUのcachelineの長さが64byteと想定している
-pub const CACHELINE_LEN: usize = 64;
+impl<C: RingBufConsumer<T>, T> Iterator for TryIter<'_, C, T> {
+    type Item = T;
 
-// reference from https://github.com/polyfractal/bounded-spsc-queue
-#[macro_export(local_inner_macros)]
-macro_rules! cacheline_pad {
-    ($N:expr) => {
-        $crate::helper::CACHELINE_LEN / std::mem::size_of::<usize>() - $N
-    };
+    #[inline]
+    fn next(&mut self) -> Option<T> {
+        self.consumer.dequeue()
+    }
+}
+
+/// Bulk operations that publish the write index once per batch.
+pub trait RingBufBatchProducer<T>: RingBufProducer<T> {
+    /// Copies as many items from the head of `items` as fit. Returns the number of items written.
+    fn enqueue_slice(&self, items: &[T]) -> usize
+    where
+        T: Copy;
+
+    /// Moves items out of `iter` until the ring is full or the iterator ends.
+    /// No more items than there are free slots are pulled from the iterator.
+    /// Returns the number of items written.
+    fn enqueue_iter<I: IntoIterator<Item = T>>(&self, iter: I) -> usize;
+}
+
+/// Bulk operations that publish the read index once per batch.
+pub trait RingBufBatchConsumer<T>: RingBufConsumer<T> {
+    /// Moves up to `dst.len()` items into the head of `dst`. Returns the number of items read,
+    /// `dst[..n]` is initialized afterwards.
+    fn dequeue_into(&self, dst: &mut [MaybeUninit<T>]) -> usize;
 }
+
+/// `try_dequeue` of a ring that the other side may close.
+/// `Empty` after the close is reported as `Disconnected`.
+#[cfg(feature = "std")]
+pub(crate) fn dequeue_or_disconnected<T>(
+    mut try_once: impl FnMut() -> Result<T, Empty>,
+    is_closed: impl FnOnce() -> bool,
+) -> Result<T, TryDequeueError> {
+    match try_once() {
+        Ok(v) => Ok(v),
+        Err(Empty) if is_closed() => {
+            // close前に書かれた要素を取りこぼさないように読み直す
+            try_once().map_err(|_| TryDequeueError::Disconnected)
+        }
+        Err(Empty) => Err(TryDequeueError::Empty),
+    }
+}
+
+/// Enqueue that waits for a free slot with the `WaitStrategy` of the ring.
+#[cfg(feature = "std")]
+pub trait RingBufBlockingProducer<T>: RingBufProducer<T> {
+    /// `true` when either side has closed the ring, enqueued items can not be delivered anymore.
+    fn is_closed(&self) -> bool;
+
+    fn capacity(&self) -> usize;
+
+    /// Number of free slots.
+    /// The consumer may dequeue concurrently, so the actual value can only be larger.
+    fn free_slots(&self) -> usize;
+
+    /// Waits with the `WaitStrategy` of the ring until `ready` returns `true`.
+    /// Returns `false` when `deadline` passes first.
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool;
+
+    fn len(&self) -> usize {
+        self.capacity() - self.free_slots()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `true` may be stale because the consumer can free a slot at any time.
+    fn is_full(&self) -> bool {
+        self.free_slots() == 0
+    }
+
+    /// `true` when `try_enqueue` may succeed or will never succeed again.
+    fn is_writable(&self) -> bool {
+        self.is_closed() || !self.is_full()
+    }
+
+    /// Waits until the item is enqueued. Fails only when the consumer is gone.
+    fn enqueue_blocking(&self, item: T) -> Result<(), Disconnected<T>>
+    where
+        Self: Sized,
+    {
+        enqueue_until(self, item, None).map_err(|e| Disconnected(e.into_inner()))
+    }
+
+    fn enqueue_timeout(&self, item: T, timeout: Duration) -> Result<(), EnqueueTimeoutError<T>>
+    where
+        Self: Sized,
+    {
+        enqueue_until(self, item, Instant::now().checked_add(timeout))
+    }
+}
+
+#[cfg(feature = "std")]
+fn enqueue_until<T, P: RingBufBlockingProducer<T>>(
+    p: &P,
+    mut item: T,
+    deadline: Option<Instant>,
+) -> Result<(), EnqueueTimeoutError<T>> {
+    loop {
+        match p.try_enqueue(item) {
+            Ok(()) => return Ok(()),
+            Err(TryEnqueueError::Disconnected(v)) => {
+                return Err(EnqueueTimeoutError::Disconnected(v))
+            }
+            Err(TryEnqueueError::Full(v)) => item = v,
+        }
+        if !p.wait_until(|| p.is_writable(), deadline) {
+            return Err(EnqueueTimeoutError::Timeout(item));
+        }
+    }
+}
+
+/// Dequeue that waits for an item with the `WaitStrategy` of the ring.
+#[cfg(feature = "std")]
+pub trait RingBufBlockingConsumer<T>: RingBufConsumer<T> {
+    /// `true` when the producer side has closed the ring.
+    /// Items enqueued before the close can still be dequeued.
+    fn is_closed(&self) -> bool;
+
+    fn capacity(&self) -> usize;
+
+    /// Number of readable items.
+    /// The producer may enqueue concurrently, so the actual value can only be larger.
+    fn len(&self) -> usize;
+
+    /// Waits with the `WaitStrategy` of the ring until `ready` returns `true`.
+    /// Returns `false` when `deadline` passes first.
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn free_slots(&self) -> usize {
+        self.capacity() - self.len()
+    }
+
+    fn is_full(&self) -> bool {
+        self.free_slots() == 0
+    }
+
+    /// `true` when `try_dequeue` may succeed or will report `Disconnected`.
+    fn is_readable(&self) -> bool {
+        self.is_closed() || !self.is_empty()
+    }
+
+    /// Waits until an item arrives. Fails only when the ring is drained and the producer is gone.
+    fn dequeue_blocking(&self) -> Result<T, Disconnected>
+    where
+        Self: Sized,
+    {
+        dequeue_until(self, None).map_err(|_| Disconnected(()))
+    }
+
+    fn dequeue_timeout(&self, timeout: Duration) -> Result<T, DequeueTimeoutError>
+    where
+        Self: Sized,
+    {
+        dequeue_until(self, Instant::now().checked_add(timeout))
+    }
+}
+
+#[cfg(feature = "std")]
+fn dequeue_until<T, C: RingBufBlockingConsumer<T>>(
+    c: &C,
+    deadline: Option<Instant>,
+) -> Result<T, DequeueTimeoutError> {
+    loop {
+        match c.try_dequeue() {
+            Ok(v) => return Ok(v),
+            Err(TryDequeueError::Disconnected) => return Err(DequeueTimeoutError::Disconnected),
+            Err(TryDequeueError::Empty) => {}
+        }
+        if !c.wait_until(|| c.is_readable(), deadline) {
+            return Err(DequeueTimeoutError::Timeout);
+        }
+    }
+}
+
+/// Aligns and pads `T` to a cache line so that it never shares a line with its neighbours.
+///
+/// The line is 128 bytes on aarch64 and powerpc64, whose cores fetch lines in 128-byte pairs
+/// or have 128-byte lines, and 64 bytes elsewhere. The `cacheline-128` feature forces 128.
+#[cfg_attr(
+    any(
+        target_arch = "aarch64",
+        target_arch = "powerpc64",
+        feature = "cacheline-128"
+    ),
+    repr(align(128))
+)]
+#[cfg_attr(
+    not(any(
+        target_arch = "aarch64",
+        target_arch = "powerpc64",
+        feature = "cacheline-128"
+    )),
+    repr(align(64))
+)]
+#[derive(Debug, Default)]
+pub struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Length of a cache line assumed on this target, see [`CachePadded`].
+pub const CACHELINE_LEN: usize = core::mem::align_of::<CachePadded<()>>();
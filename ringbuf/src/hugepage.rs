@@ -0,0 +1,126 @@
+//! Storage on huge pages like `RingBuffer4` in `reference/main.cc`.
+//!
+//! [`allocate_huge`] tries explicit huge pages (`MAP_HUGETLB`, needs pages reserved in
+//! `/proc/sys/vm/nr_hugepages`), then transparent huge pages (`madvise(MADV_HUGEPAGE)`),
+//! then the heap, and reports which one it got.
+
+use crate::helper::{allocate_buffer, Backing};
+
+/// Size of a huge page on x86_64 and most aarch64 kernels.
+pub const HUGE_PAGE_LEN: usize = 2 << 20;
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+/// Length in bytes of the mapping for `len` elements of `T`, rounded up to [`HUGE_PAGE_LEN`].
+fn mapping_len<T>(len: usize) -> Option<usize> {
+    let bytes = len.checked_mul(core::mem::size_of::<T>())?;
+    let bytes = bytes.checked_add(HUGE_PAGE_LEN - 1)? & !(HUGE_PAGE_LEN - 1);
+    isize::try_from(bytes).ok().map(|_| bytes)
+}
+
+/// Allocates uninitialized memory for `capacity.next_power_of_two()` elements of `T`
+/// on huge pages when possible. Returns the pointer, the number of elements and the backing.
+///
+/// # Safety
+/// The returned memory is uninitialized and must be released with
+/// [`crate::helper::deallocate_buffer`] using the same length and backing.
+pub unsafe fn allocate_huge<T>(capacity: usize) -> (*mut T, usize, Backing) {
+    let len = capacity.next_power_of_two();
+    #[cfg(target_os = "linux")]
+    if core::mem::size_of::<T>() != 0 {
+        if let Some(bytes) = mapping_len::<T>(len) {
+            if let Some(ptr) = map_hugetlb(bytes) {
+                return (ptr as *mut T, len, Backing::HugeTlb);
+            }
+            if let Some(ptr) = map_transparent(bytes) {
+                return (ptr as *mut T, len, Backing::TransparentHugePage);
+            }
+        }
+    }
+    (allocate_buffer(capacity), len, Backing::Heap)
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn map_hugetlb(bytes: usize) -> Option<*mut u8> {
+    let ptr = libc::mmap(
+        core::ptr::null_mut(),
+        bytes,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB,
+        -1,
+        0,
+    );
+    (ptr != libc::MAP_FAILED).then_some(ptr as *mut u8)
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn map_transparent(bytes: usize) -> Option<*mut u8> {
+    // THPは2MiB境界に揃っていないと使われないので、1ページ多く確保して前後を切り落とす
+    let total = bytes.checked_add(HUGE_PAGE_LEN)?;
+    let raw = libc::mmap(
+        core::ptr::null_mut(),
+        total,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if raw == libc::MAP_FAILED {
+        return None;
+    }
+    let raw = raw as usize;
+    let aligned = (raw + HUGE_PAGE_LEN - 1) & !(HUGE_PAGE_LEN - 1);
+    if raw < aligned {
+        libc::munmap(raw as *mut libc::c_void, aligned - raw);
+    }
+    let tail = raw + total - (aligned + bytes);
+    if tail != 0 {
+        libc::munmap((aligned + bytes) as *mut libc::c_void, tail);
+    }
+    let ptr = aligned as *mut libc::c_void;
+    if libc::madvise(ptr, bytes, libc::MADV_HUGEPAGE) != 0 {
+        libc::munmap(ptr, bytes);
+        return None;
+    }
+    Some(ptr as *mut u8)
+}
+
+/// Unmaps memory returned by [`allocate_huge`] with a huge page backing.
+///
+/// # Safety
+/// `ptr` and `len` must be the values returned by [`allocate_huge`],
+/// and the memory must not be used afterwards.
+#[cfg(target_os = "linux")]
+pub unsafe fn deallocate_huge<T>(ptr: *mut T, len: usize) {
+    libc::munmap(ptr as *mut libc::c_void, mapping_len::<T>(len).unwrap());
+}
+
+#[cfg(not(target_os = "linux"))]
+/// # Safety
+/// Same as the Linux version.
+pub unsafe fn deallocate_huge<T>(_ptr: *mut T, _len: usize) {
+    unreachable!("allocate_huge never maps huge pages on this platform");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::deallocate_buffer;
+
+    #[test]
+    fn test_allocate_huge() {
+        // どのbackingになるかは環境次第なので、読み書きと解放ができることだけ確かめる
+        let (ptr, len, backing) = unsafe { allocate_huge::<u64>(3 << 20) };
+        assert_eq!(len, 4 << 20);
+        unsafe {
+            ptr.write(1);
+            ptr.add(len - 1).write(2);
+            assert_eq!(ptr.read() + ptr.add(len - 1).read(), 3);
+            deallocate_buffer(ptr, len, backing);
+        }
+        assert_eq!(mapping_len::<u8>(1), Some(HUGE_PAGE_LEN));
+        assert_eq!(
+            mapping_len::<u64>(HUGE_PAGE_LEN / 8 + 1),
+            Some(HUGE_PAGE_LEN * 2)
+        );
+    }
+}
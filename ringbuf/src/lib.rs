@@ -1,5 +1,532 @@
-mod helper;
+//! Without the default `std` feature the crate is `no_std`. r0, r1 and r8 (the r3 algorithm on
+//! inline slots) are built on `core` atomics with caller-supplied [`Storage`], `alloc` adds the
+//! heap and mapped storages, and `std` adds the rings shared by `Arc` and the wait strategies.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod error;
+#[cfg(feature = "std")]
+pub mod framed;
+pub mod helper;
+#[cfg(feature = "alloc")]
+pub mod hugepage;
+#[cfg(feature = "alloc")]
+pub mod mirror;
 pub mod r0;
 pub mod r1;
+#[cfg(feature = "std")]
 pub mod r2;
+#[cfg(feature = "std")]
 pub mod r3;
+#[cfg(feature = "std")]
+pub mod r4;
+#[cfg(feature = "std")]
+pub mod r5;
+#[cfg(feature = "std")]
+pub mod r6;
+#[cfg(feature = "std")]
+pub mod r7;
+pub mod r8;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod shm;
+pub mod storage;
+#[cfg(feature = "std")]
+mod vyukov;
+#[cfg(feature = "std")]
+pub mod wait;
+
+pub use error::{
+    DequeueTimeoutError, Disconnected, Empty, EnqueueTimeoutError, Full, TryDequeueError,
+    TryDequeueLossyError, TryEnqueueError,
+};
+pub use helper::{
+    Backing, RingBufBatchConsumer, RingBufBatchProducer, RingBufConsumer, RingBufProducer,
+    RingBufTrait, TryIter,
+};
+#[cfg(feature = "std")]
+pub use helper::{RingBufBlockingConsumer, RingBufBlockingProducer};
+pub use storage::Storage;
+#[cfg(feature = "std")]
+pub use wait::WaitStrategy;
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        mem::MaybeUninit,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use crate::{
+        wait, DequeueTimeoutError, Empty, Full, RingBufBatchConsumer, RingBufBatchProducer,
+        RingBufBlockingConsumer, RingBufBlockingProducer, RingBufConsumer, RingBufProducer,
+        RingBufTrait, TryDequeueError, TryEnqueueError, WaitStrategy,
+    };
+
+    fn check_full_ringbuf<R: RingBufTrait<String>>(mut ringbuf: R, cap: usize) {
+        for i in 0..cap {
+            assert_eq!(ringbuf.try_enqueue(i.to_string()), Ok(()));
+        }
+        assert_eq!(
+            ringbuf.try_enqueue("rejected".to_string()),
+            Err(Full("rejected".to_string()))
+        );
+        for i in 0..cap {
+            assert_eq!(ringbuf.try_dequeue(), Ok(i.to_string()));
+        }
+        assert_eq!(ringbuf.try_dequeue(), Err(Empty));
+    }
+
+    fn check_full_pc<P: RingBufProducer<String>, C: RingBufConsumer<String>>(
+        p: P,
+        c: C,
+        cap: usize,
+    ) {
+        for i in 0..cap {
+            assert_eq!(p.try_enqueue(i.to_string()), Ok(()));
+        }
+        let err = p.try_enqueue("rejected".to_string()).unwrap_err();
+        assert!(err.is_full());
+        assert_eq!(err.into_inner(), "rejected");
+        for i in 0..cap {
+            assert_eq!(c.try_dequeue(), Ok(i.to_string()));
+        }
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Empty));
+        assert_eq!(p.try_enqueue("retry".to_string()), Ok(()));
+        assert_eq!(c.dequeue(), Some("retry".to_string()));
+    }
+
+    #[test]
+    fn test_full_returns_item() {
+        let cap = 10;
+        check_full_ringbuf(crate::r0::RingBuf::<String>::with_capacity(cap), cap);
+        check_full_ringbuf(crate::r1::RingBuf::<String>::with_capacity(cap), cap);
+        let (p, c) = crate::r2::make::<String>(cap);
+        check_full_pc(p, c, cap);
+        let (p, c) = crate::r3::make::<String>(cap);
+        check_full_pc(p, c, cap);
+        let (p, c) = crate::r4::make::<String>(cap);
+        check_full_pc(p, c, cap);
+        let (p, c) = crate::r5::make::<String>(cap);
+        check_full_pc(p, c, cap);
+        let (p, c) = crate::r6::make::<String>(cap);
+        check_full_pc(p, c, cap);
+        let mut buf = crate::r8::Buffer::<String, 16>::new();
+        let (p, c) = buf.split();
+        check_full_pc(p, c, 16);
+    }
+
+    #[test]
+    fn test_error_conversion() {
+        let e: TryEnqueueError<i32> = Full(3).into();
+        assert_eq!(e, TryEnqueueError::Full(3));
+        let e: TryDequeueError = Empty.into();
+        assert!(e.is_empty());
+    }
+
+    fn check_batch_pc<P: RingBufBatchProducer<u32>, C: RingBufBatchConsumer<u32>>(
+        p: P,
+        c: C,
+        cap: usize,
+    ) {
+        let src = (0..(cap as u32 * 2)).collect::<Vec<_>>();
+        let mut dst = vec![MaybeUninit::<u32>::uninit(); cap * 2];
+        let mut next = 0;
+        // 開始位置をずらしながら末尾を跨ぐ書き込み・読み出しを試す
+        for shift in 0..(cap * 2) {
+            assert_eq!(p.enqueue_slice(&src[..shift % cap]), shift % cap);
+            assert_eq!(c.dequeue_into(&mut dst), shift % cap);
+
+            let written = p.enqueue_slice(&src[next..next + cap + 1]);
+            assert_eq!(written, cap);
+            assert_eq!(p.enqueue_slice(&src[..1]), 0);
+            let read = c.dequeue_into(&mut dst[..cap - 1]);
+            assert_eq!(read, cap - 1);
+            let read = read + c.dequeue_into(&mut dst[read..]);
+            assert_eq!(read, cap);
+            for (i, v) in dst[..read].iter().enumerate() {
+                assert_eq!(unsafe { v.assume_init() }, src[next + i]);
+            }
+            assert_eq!(c.dequeue_into(&mut dst), 0);
+            next = (next + 1) % cap;
+        }
+
+        let mut iter = 0..(cap as u32 + 5);
+        assert_eq!(p.enqueue_iter(&mut iter), cap);
+        assert_eq!(iter.next(), Some(cap as u32));
+        for i in 0..cap as u32 {
+            assert_eq!(c.dequeue(), Some(i));
+        }
+        assert_eq!(p.enqueue_iter(0..3), cap.min(3));
+        assert_eq!(c.dequeue_into(&mut dst), cap.min(3));
+    }
+
+    #[test]
+    fn test_batch() {
+        for cap in [1, 3, 8, 10] {
+            let (p, c) = crate::r2::make::<u32>(cap);
+            check_batch_pc(p, c, cap);
+            let (p, c) = crate::r3::make::<u32>(cap);
+            check_batch_pc(p, c, cap);
+        }
+    }
+
+    #[test]
+    fn test_drain() {
+        let (p, mut c) = crate::r2::make::<String>(8);
+        assert_eq!(p.enqueue_iter((0..6).map(|i| i.to_string())), 6);
+        let mut drain = c.drain(4);
+        assert_eq!(drain.len(), 4);
+        assert_eq!(drain.next(), Some("0".to_string()));
+        assert_eq!(drain.next(), Some("1".to_string()));
+        drop(drain);
+        assert_eq!(
+            c.drain(usize::MAX).collect::<Vec<_>>(),
+            ["2", "3", "4", "5"]
+        );
+        assert_eq!(p.enqueue_iter((0..8).map(|i| i.to_string())), 8);
+
+        let (p, mut c) = crate::r3::make::<String>(8);
+        assert_eq!(p.enqueue_iter((0..6).map(|i| i.to_string())), 6);
+        let mut drain = c.drain(10);
+        assert_eq!(drain.next(), Some("0".to_string()));
+        // leakしても読み出し済みの要素は再度返らない
+        std::mem::forget(drain);
+        assert_eq!(c.dequeue(), Some("1".to_string()));
+        assert_eq!(c.drain(usize::MAX).count(), 4);
+        assert_eq!(p.enqueue_iter((0..8).map(|i| i.to_string())), 8);
+    }
+
+    fn check_try_iter<P: RingBufProducer<u32>, C: RingBufConsumer<u32>>(p: P, c: C) {
+        assert_eq!(c.try_iter().next(), None);
+        for i in 0..4 {
+            assert!(p.enqueue(i));
+        }
+        let mut iter = c.try_iter();
+        assert_eq!(iter.next(), Some(0));
+        // 途中で追加されたものも読む
+        assert!(p.enqueue(4));
+        assert_eq!(iter.collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert!(p.enqueue(5));
+        assert_eq!(c.try_iter().sum::<u32>(), 5);
+    }
+
+    #[test]
+    fn test_iter() {
+        let (p, c) = crate::r2::make::<u32>(8);
+        check_try_iter(p, c);
+        let (p, c) = crate::r3::make::<u32>(8);
+        check_try_iter(p, c);
+        let mut buf = crate::r8::Buffer::<u32, 8>::new();
+        let (p, c) = buf.split();
+        check_try_iter(p, c);
+
+        // 入りきらない分はiteratorに残る
+        let (mut p, c) = crate::r2::make::<u32>(4);
+        let mut src = 0..6;
+        p.extend(&mut src);
+        assert_eq!(src.next(), Some(4));
+        assert_eq!(c.try_iter().collect::<Vec<_>>(), [0, 1, 2, 3]);
+        let (mut p, mut c) = crate::r3::make::<u32>(4);
+        p.extend([7, 8]);
+        assert_eq!(c.drain(usize::MAX).collect::<Vec<_>>(), [7, 8]);
+        // 個数が欲しい場合はenqueue_iter
+        assert_eq!(p.enqueue_iter(0..6), 4);
+        assert_eq!(c.try_iter().count(), 4);
+
+        let (p, c) = crate::r2::make_from_iter(0..5_u32);
+        assert_eq!((p.capacity(), c.len()), (5, 5));
+        assert!(!p.enqueue(5));
+        assert_eq!(c.try_iter().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        let (p, mut c) = crate::r3::make_from_iter(["a".to_string()]);
+        assert!(p.is_full());
+        assert_eq!(c.drain(usize::MAX).collect::<Vec<_>>(), ["a"]);
+        let (p, c) = crate::r3::make_from_iter(std::iter::empty::<u32>());
+        assert!(c.is_empty() && p.enqueue(1));
+    }
+
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn check_producer_drop<P: RingBufProducer<u32>, C: RingBufConsumer<u32>>(p: P, c: C) {
+        assert!(p.enqueue(1));
+        assert!(p.enqueue(2));
+        drop(p);
+        assert_eq!(c.try_dequeue(), Ok(1));
+        assert_eq!(c.try_dequeue(), Ok(2));
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+    }
+
+    fn check_consumer_drop<P: RingBufProducer<String>, C: RingBufConsumer<String>>(p: P, c: C) {
+        assert!(p.enqueue("a".to_string()));
+        drop(c);
+        let err = p.try_enqueue("b".to_string()).unwrap_err();
+        assert!(err.is_disconnected());
+        assert_eq!(err.into_inner(), "b");
+    }
+
+    fn check_drop_items<P: RingBufProducer<DropCounter>, C: RingBufConsumer<DropCounter>>(
+        p: P,
+        c: C,
+        producer_first: bool,
+    ) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            assert!(p.enqueue(DropCounter(count.clone())));
+        }
+        drop(c.dequeue());
+        if producer_first {
+            drop(p);
+            drop(c);
+        } else {
+            drop(c);
+            drop(p);
+        }
+        count
+    }
+
+    fn check_producer_panic<P: RingBufProducer<u32> + Send + 'static, C: RingBufConsumer<u32>>(
+        p: P,
+        c: C,
+    ) {
+        let h = std::thread::spawn(move || {
+            for i in 0..100 {
+                while !p.enqueue(i) {}
+            }
+            panic!("producer failed");
+        });
+        let mut expect = 0;
+        loop {
+            match c.try_dequeue() {
+                Ok(v) => {
+                    assert_eq!(v, expect);
+                    expect += 1;
+                }
+                Err(TryDequeueError::Empty) => std::hint::spin_loop(),
+                Err(TryDequeueError::Disconnected) => break,
+            }
+        }
+        assert_eq!(expect, 100);
+        assert!(h.join().is_err());
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (p, c) = crate::r2::make::<u32>(4);
+        check_producer_drop(p, c);
+        let (p, c) = crate::r3::make::<u32>(4);
+        check_producer_drop(p, c);
+        let (p, c) = crate::r4::make::<u32>(4);
+        check_producer_drop(p, c);
+        let (p, c) = crate::r5::make::<u32>(4);
+        check_producer_drop(p, c);
+        let (p, c) = crate::r6::make::<u32>(4);
+        check_producer_drop(p, c);
+        let (p, c) = crate::r7::make::<u32>(4);
+        check_producer_drop(p, c);
+
+        let (p, c) = crate::r2::make::<String>(4);
+        check_consumer_drop(p, c);
+        let (p, c) = crate::r3::make::<String>(4);
+        check_consumer_drop(p, c);
+        let (p, c) = crate::r4::make::<String>(4);
+        check_consumer_drop(p, c);
+        let (p, c) = crate::r5::make::<String>(4);
+        check_consumer_drop(p, c);
+
+        for producer_first in [true, false] {
+            let (p, c) = crate::r2::make::<DropCounter>(4);
+            let count = check_drop_items(p, c, producer_first);
+            assert_eq!(count.load(Ordering::Relaxed), 3);
+            let (p, c) = crate::r3::make::<DropCounter>(4);
+            let count = check_drop_items(p, c, producer_first);
+            assert_eq!(count.load(Ordering::Relaxed), 3);
+            let (p, c) = crate::r4::make::<DropCounter>(4);
+            let count = check_drop_items(p, c, producer_first);
+            assert_eq!(count.load(Ordering::Relaxed), 3);
+            let (p, c) = crate::r5::make::<DropCounter>(4);
+            let count = check_drop_items(p, c, producer_first);
+            assert_eq!(count.load(Ordering::Relaxed), 3);
+        }
+
+        let (p, c) = crate::r2::make::<u32>(8);
+        check_producer_panic(p, c);
+        let (p, c) = crate::r3::make::<u32>(8);
+        check_producer_panic(p, c);
+        let (p, c) = crate::r4::make::<u32>(8);
+        check_producer_panic(p, c);
+        let (p, c) = crate::r5::make::<u32>(8);
+        check_producer_panic(p, c);
+        let (p, c) = crate::r6::make::<u32>(8);
+        check_producer_panic(p, c);
+    }
+
+    #[test]
+    fn test_close() {
+        let (p, c) = crate::r3::make::<u32>(4);
+        assert!(!p.is_closed() && !c.is_closed());
+        assert!(p.enqueue(1));
+        p.close();
+        assert!(p.is_closed() && c.is_closed());
+        assert_eq!(p.try_enqueue(2), Err(TryEnqueueError::Disconnected(2)));
+        assert_eq!(p.enqueue_slice(&[3, 4]), 0);
+        assert_eq!(c.try_dequeue(), Ok(1));
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+
+        let (p, c) = crate::r2::make::<u32>(4);
+        p.close();
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+        drop(c);
+        assert!(p.is_closed());
+    }
+
+    fn check_blocking<
+        P: RingBufBlockingProducer<u32> + Send + 'static,
+        C: RingBufBlockingConsumer<u32>,
+    >(
+        p: P,
+        c: C,
+    ) {
+        let count = 1000;
+        let h = std::thread::spawn(move || {
+            for i in 0..count {
+                p.enqueue_blocking(i).unwrap();
+            }
+        });
+        for i in 0..count {
+            assert_eq!(c.dequeue_blocking(), Ok(i));
+        }
+        h.join().unwrap();
+        assert!(c.dequeue_blocking().is_err());
+    }
+
+    fn check_timeout<P: RingBufBlockingProducer<u32>, C: RingBufBlockingConsumer<u32>>(p: P, c: C) {
+        let timeout = std::time::Duration::from_millis(10);
+        assert_eq!(
+            c.dequeue_timeout(timeout),
+            Err(DequeueTimeoutError::Timeout)
+        );
+        while p.enqueue(0) {}
+        let err = p.enqueue_timeout(7, timeout).unwrap_err();
+        assert!(err.is_timeout());
+        assert_eq!(err.into_inner(), 7);
+        assert_eq!(c.dequeue_timeout(timeout), Ok(0));
+        assert_eq!(p.enqueue_timeout(7, timeout), Ok(()));
+        drop(c);
+        assert!(p.enqueue_timeout(8, timeout).unwrap_err().is_disconnected());
+    }
+
+    fn check_occupancy<P: RingBufBlockingProducer<u32>, C: RingBufBlockingConsumer<u32>>(
+        p: P,
+        c: C,
+        cap: usize,
+    ) {
+        assert_eq!(p.capacity(), cap);
+        assert_eq!(c.capacity(), cap);
+        assert!(p.is_empty() && c.is_empty());
+        for i in 0..cap {
+            assert_eq!(p.len(), i);
+            assert_eq!(c.len(), i);
+            assert_eq!(p.free_slots(), cap - i);
+            assert_eq!(c.free_slots(), cap - i);
+            assert!(p.enqueue(i as u32));
+        }
+        assert!(p.is_full() && c.is_full());
+        assert_eq!(c.dequeue(), Some(0));
+        assert_eq!(p.len(), cap - 1);
+        assert_eq!(p.free_slots(), 1);
+        while c.dequeue().is_some() {}
+        assert!(p.is_empty() && c.is_empty());
+    }
+
+    #[test]
+    fn test_occupancy() {
+        let (p, c) = crate::r2::make::<u32>(5);
+        check_occupancy(p, c, 5);
+        let (p, c) = crate::r3::make::<u32>(5);
+        check_occupancy(p, c, 5);
+        let (p, c) = crate::r4::make::<u32>(5);
+        check_occupancy(p, c, 5);
+        let (p, c) = crate::r5::make::<u32>(5);
+        check_occupancy(p, c, 5);
+    }
+
+    // 2の冪でない容量でも、周回をまたいで要求された容量ちょうどで満杯になる
+    fn check_exact_capacity<P: RingBufBlockingProducer<u32>, C: RingBufBlockingConsumer<u32>>(
+        p: P,
+        c: C,
+        cap: usize,
+    ) {
+        for round in 0..3 {
+            for i in 0..cap as u32 {
+                assert!(p.enqueue(i + round));
+            }
+            assert!(p.is_full());
+            assert!(!p.enqueue(99));
+            for i in 0..cap as u32 {
+                assert_eq!(c.dequeue(), Some(i + round));
+            }
+            assert!(c.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_exact_capacity() {
+        for cap in [1, 3, 4, 5] {
+            let (p, c) = crate::r2::make::<u32>(cap);
+            check_exact_capacity(p, c, cap);
+            let (p, c) = crate::r3::make::<u32>(cap);
+            check_exact_capacity(p, c, cap);
+            let (p, c) = crate::r4::make::<u32>(cap);
+            check_exact_capacity(p, c, cap);
+            let (p, c) = crate::r5::make::<u32>(cap);
+            check_exact_capacity(p, c, cap);
+        }
+    }
+
+    fn check_wait_strategy<W: WaitStrategy + Default + 'static>() {
+        let (p, c) = crate::r2::make_with_wait::<u32, W>(16, W::default());
+        check_blocking(p, c);
+        let (p, c) = crate::r3::make_with_wait::<u32, W>(16, W::default());
+        check_blocking(p, c);
+        let (p, c) = crate::r4::make_with_wait::<u32, W>(16, W::default());
+        check_blocking(p, c);
+        let (p, c) = crate::r5::make_with_wait::<u32, W>(16, W::default());
+        check_blocking(p, c);
+        let (p, c) = crate::r2::make_with_wait::<u32, W>(4, W::default());
+        check_timeout(p, c);
+        let (p, c) = crate::r3::make_with_wait::<u32, W>(4, W::default());
+        check_timeout(p, c);
+        let (p, c) = crate::r4::make_with_wait::<u32, W>(4, W::default());
+        check_timeout(p, c);
+        let (p, c) = crate::r5::make_with_wait::<u32, W>(4, W::default());
+        check_timeout(p, c);
+
+        // 待機中のConsumerはProducerのdropで起きる
+        let (p, c) = crate::r3::make_with_wait::<u32, W>(4, W::default());
+        let h = std::thread::spawn(move || c.dequeue_blocking());
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        drop(p);
+        assert!(h.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_blocking() {
+        check_wait_strategy::<wait::BusySpin>();
+        check_wait_strategy::<wait::SpinLoopHint>();
+        check_wait_strategy::<wait::Yield>();
+        check_wait_strategy::<wait::Backoff>();
+        check_wait_strategy::<wait::Park>();
+        #[cfg(target_os = "linux")]
+        check_wait_strategy::<wait::Futex>();
+    }
+}
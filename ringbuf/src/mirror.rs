@@ -0,0 +1,143 @@
+//! Storage whose pages are mapped twice back-to-back, so the slot after the last one is the
+//! first one again and any run of up to `len` elements is a single contiguous slice.
+//!
+//! Only Linux is supported (`memfd_create` + `mmap`). Elsewhere, and whenever mapping fails,
+//! [`allocate_mirrored`] returns `None` and the caller falls back to
+//! [`crate::helper::allocate_buffer`].
+
+/// Number of elements a mirrored allocation for `capacity` elements of `T` has.
+/// A power of two and a multiple of the page size in bytes, so it can be larger than
+/// `capacity.next_power_of_two()` for small rings.
+pub fn mirrored_len<T>(capacity: usize) -> usize {
+    let size = core::mem::size_of::<T>().max(1);
+    let page = page_size();
+    // sizeとpageの最大公約数はどちらも2の冪なので、そのうち小さい方
+    let gcd = 1 << size.trailing_zeros().min(page.trailing_zeros());
+    capacity.next_power_of_two().max(page / gcd)
+}
+
+#[cfg(target_os = "linux")]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn page_size() -> usize {
+    4096
+}
+
+/// Maps [`mirrored_len`] elements of `T` twice back-to-back.
+/// Returns the head of the first mapping and the number of elements, or `None` when the
+/// mapping is not possible.
+///
+/// # Safety
+/// The returned memory is uninitialized and must be released with [`deallocate_mirrored`]
+/// using the same length.
+#[cfg(target_os = "linux")]
+pub unsafe fn allocate_mirrored<T>(capacity: usize) -> Option<(*mut T, usize)> {
+    use core::ptr;
+
+    if core::mem::size_of::<T>() == 0 {
+        return None;
+    }
+    let len = mirrored_len::<T>(capacity);
+    let bytes = len.checked_mul(core::mem::size_of::<T>())?;
+    let total = bytes.checked_mul(2)?;
+    if isize::try_from(total).is_err() {
+        return None;
+    }
+
+    let fd = libc::memfd_create(c"ringbuf".as_ptr(), libc::MFD_CLOEXEC);
+    if fd < 0 {
+        return None;
+    }
+    if libc::ftruncate(fd, bytes as libc::off_t) != 0 {
+        libc::close(fd);
+        return None;
+    }
+    // 先に2倍の領域を確保しておき、その前半と後半に同じfdをMAP_FIXEDで重ねる
+    let base = libc::mmap(
+        ptr::null_mut(),
+        total,
+        libc::PROT_NONE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if base == libc::MAP_FAILED {
+        libc::close(fd);
+        return None;
+    }
+    for half in [base, (base as *mut u8).add(bytes) as *mut libc::c_void] {
+        let p = libc::mmap(
+            half,
+            bytes,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_FIXED,
+            fd,
+            0,
+        );
+        if p == libc::MAP_FAILED {
+            libc::munmap(base, total);
+            libc::close(fd);
+            return None;
+        }
+    }
+    // mapが残っている間はfdを閉じてもページは解放されない
+    libc::close(fd);
+    Some((base as *mut T, len))
+}
+
+#[cfg(not(target_os = "linux"))]
+/// # Safety
+/// Same as the Linux version.
+pub unsafe fn allocate_mirrored<T>(_capacity: usize) -> Option<(*mut T, usize)> {
+    None
+}
+
+/// Unmaps memory returned by [`allocate_mirrored`].
+///
+/// # Safety
+/// `ptr` and `len` must be the values returned by [`allocate_mirrored`],
+/// and the memory must not be used afterwards.
+#[cfg(target_os = "linux")]
+pub unsafe fn deallocate_mirrored<T>(ptr: *mut T, len: usize) {
+    libc::munmap(
+        ptr as *mut libc::c_void,
+        len * core::mem::size_of::<T>() * 2,
+    );
+}
+
+#[cfg(not(target_os = "linux"))]
+/// # Safety
+/// Same as the Linux version.
+pub unsafe fn deallocate_mirrored<T>(_ptr: *mut T, _len: usize) {
+    unreachable!("allocate_mirrored never succeeds on this platform");
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirrored() {
+        let (ptr, len) = unsafe { allocate_mirrored::<u32>(10) }.unwrap();
+        assert!(len.is_power_of_two());
+        assert_eq!(len * 4 % page_size(), 0);
+        unsafe {
+            for i in 0..len {
+                ptr.add(i).write(i as u32);
+            }
+            // 後半は前半と同じページ
+            let mirror = core::slice::from_raw_parts(ptr.add(len - 2), 4);
+            assert_eq!(mirror, [len as u32 - 2, len as u32 - 1, 0, 1]);
+            ptr.add(len).write(100);
+            assert_eq!(ptr.read(), 100);
+            deallocate_mirrored(ptr, len);
+        }
+
+        // 要素の大きさが2の冪でなくても末尾で要素の境界がずれない
+        assert_eq!(mirrored_len::<[u8; 12]>(1) * 12 % page_size(), 0);
+        assert!(unsafe { allocate_mirrored::<()>(4) }.is_none());
+    }
+}
@@ -1,26 +1,48 @@
-use std::{alloc::Layout, mem, ptr};
+use core::ptr;
 
-use crate::helper::{allocate_buffer, RingBufTrait};
+use crate::{
+    error::{Empty, Full},
+    helper::{Backing, RingBufTrait},
+    storage::{DefaultStorage, Storage},
+};
 
 /// Ringbuffer
 /// index calculation: by modulo
+/// capacity: exact, any non-zero size without rounding up
 /// don't support multi-threding
 #[derive(Debug)]
-pub struct RingBuf {
-    buf: *mut usize,
+pub struct RingBuf<T, S: Storage<T> = DefaultStorage<T>> {
+    buf: *mut T,
+    storage: S,
     capacity: usize,
-    allocated_size: usize,
+    // read_idx, write_idx は [0, 2 * capacity) を巡回する
+    // 満杯と空を区別するために capacity の2倍の範囲を使う
     read_idx: usize,
     write_idx: usize,
 }
 
-impl RingBuf {
+#[cfg(feature = "alloc")]
+impl<T> RingBuf<T> {
     pub fn with_capacity(capacity: usize) -> Self {
-        let ptr = unsafe { allocate_buffer(capacity) };
+        assert!(0 < capacity, "capacity must be greater than 0");
+        assert!(capacity <= usize::MAX / 2, "capacity overflow");
+        Self::with_storage(crate::storage::Allocated::exact(capacity), capacity)
+    }
+}
+
+impl<T, S: Storage<T>> RingBuf<T, S> {
+    /// Builds a ring on `storage`, which must have at least `capacity` slots.
+    pub fn with_storage(mut storage: S, capacity: usize) -> Self {
+        assert!(0 < capacity, "capacity must be greater than 0");
+        assert!(capacity <= usize::MAX / 2, "capacity overflow");
+        assert!(
+            capacity <= storage.slots(),
+            "storage is smaller than capacity"
+        );
         Self {
-            buf: ptr,
+            buf: storage.as_mut_ptr(),
+            storage,
             capacity,
-            allocated_size: capacity.next_power_of_two(),
             read_idx: 0,
             write_idx: 0,
         }
@@ -28,55 +50,162 @@ impl RingBuf {
 
     #[inline]
     fn to_ptr(&self, pos: usize) -> usize {
-        pos % (self.allocated_size - 1)
+        pos % self.capacity
     }
 
     #[inline]
-    unsafe fn load(&self, pos: usize) -> usize {
+    fn next_idx(&self, idx: usize) -> usize {
+        let next = idx + 1;
+        if next == self.capacity * 2 {
+            0
+        } else {
+            next
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        if self.read_idx <= self.write_idx {
+            self.write_idx - self.read_idx
+        } else {
+            self.write_idx + self.capacity * 2 - self.read_idx
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Where the storage is allocated.
+    pub fn backing(&self) -> Backing {
+        self.storage.backing()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity
+    }
+
+    pub fn free_slots(&self) -> usize {
+        self.capacity - self.len()
+    }
+
+    #[inline]
+    unsafe fn load(&self, pos: usize) -> T {
         let ptr = self.buf.add(pos);
         ptr::read(ptr)
     }
 
     #[inline]
-    unsafe fn store(&self, pos: usize, v: usize) {
+    unsafe fn store(&self, pos: usize, v: T) {
         let ptr = self.buf.add(pos);
         ptr::write(&mut *ptr, v);
     }
 }
 
-impl RingBufTrait<usize> for RingBuf {
-    fn enqueue(&mut self, item: usize) -> bool {
-        if (self.write_idx - self.read_idx) == self.capacity {
-            return false;
+impl<T, S: Storage<T>> RingBufTrait<T> for RingBuf<T, S> {
+    fn try_enqueue(&mut self, item: T) -> Result<(), Full<T>> {
+        if self.is_full() {
+            return Err(Full(item));
         }
         unsafe {
             self.store(self.to_ptr(self.write_idx), item);
         }
-        self.write_idx += 1;
-        true
+        self.write_idx = self.next_idx(self.write_idx);
+        Ok(())
     }
 
-    fn dequeue(&mut self) -> Option<usize> {
+    fn try_dequeue(&mut self) -> Result<T, Empty> {
         if self.read_idx == self.write_idx {
-            return None;
+            return Err(Empty);
         }
         let item = unsafe { self.load(self.to_ptr(self.read_idx)) };
-        self.read_idx += 1;
-        Some(item)
+        self.read_idx = self.next_idx(self.read_idx);
+        Ok(item)
     }
 }
 
-impl Drop for RingBuf {
+impl<T, S: Storage<T>> Drop for RingBuf<T, S> {
     fn drop(&mut self) {
+        // 領域はstorageのdropで解放される
         while self.dequeue().is_some() {}
+    }
+}
 
-        unsafe {
-            let layout = Layout::from_size_align(
-                self.allocated_size * mem::size_of::<usize>(),
-                mem::align_of::<usize>(),
-            )
-            .unwrap();
-            std::alloc::dealloc(self.buf as *mut u8, layout);
+#[cfg(test)]
+mod tests {
+    use super::RingBuf;
+    use crate::{error::Full, RingBufTrait};
+
+    #[test]
+    fn test_exact_capacity() {
+        for cap in [1, 2, 3, 5, 7, 8, 16, 1000] {
+            let mut rb = RingBuf::<usize>::with_capacity(cap);
+            for i in 0..cap {
+                assert!(rb.enqueue(i));
+            }
+            assert!(rb.is_full());
+            assert_eq!(rb.len(), cap);
+            assert_eq!(rb.try_enqueue(cap), Err(Full(cap)));
+            for i in 0..cap {
+                assert_eq!(rb.dequeue(), Some(i));
+            }
+            assert_eq!(rb.dequeue(), None);
+        }
+    }
+
+    #[test]
+    fn test_every_wrap_position() {
+        for cap in [1, 2, 3, 4, 5, 6, 7, 8, 9, 15, 16, 17] {
+            let mut rb = RingBuf::<String>::with_capacity(cap);
+            let mut next = 0;
+            // 2 * capacity 周期を超えて全ての開始位置から満杯まで書いて読む
+            for _ in 0..(cap * 2 + 1) {
+                for i in 0..cap {
+                    assert_eq!(rb.try_enqueue((next + i).to_string()), Ok(()));
+                }
+                assert!(!rb.enqueue("overflow".to_string()));
+                for i in 0..cap {
+                    assert_eq!(rb.dequeue(), Some((next + i).to_string()));
+                }
+                assert_eq!(rb.dequeue(), None);
+                next += cap;
+
+                // 開始位置を1つずらす
+                assert!(rb.enqueue(next.to_string()));
+                assert_eq!(rb.dequeue(), Some(next.to_string()));
+                next += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let cap = 1000;
+        let mut rb = RingBuf::<usize>::with_capacity(cap);
+        let mut write = 0;
+        let mut read = 0;
+        for round in 0..50 {
+            for _ in 0..(round * 37 % cap) {
+                if rb.enqueue(write) {
+                    write += 1;
+                }
+            }
+            for _ in 0..(round * 53 % cap) {
+                if let Some(v) = rb.dequeue() {
+                    assert_eq!(v, read);
+                    read += 1;
+                }
+            }
+            assert!(write - read <= cap);
+        }
+        while let Some(v) = rb.dequeue() {
+            assert_eq!(v, read);
+            read += 1;
         }
+        assert_eq!(read, write);
     }
 }
@@ -1,26 +1,45 @@
-use std::{alloc::Layout, mem, ptr};
+use core::ptr;
 
-use crate::helper::{allocate_buffer, RingBufTrait};
+use crate::{
+    error::{Empty, Full},
+    helper::{Backing, RingBufTrait},
+    storage::{usable_slots, DefaultStorage, Storage},
+};
 
 /// Ringbuffer
-/// index calculation: by modulo
+/// index calculation: by and
 /// don't support multi-threding
 #[derive(Debug)]
-pub struct RingBuf {
-    buf: *mut usize,
+pub struct RingBuf<T, S: Storage<T> = DefaultStorage<T>> {
+    buf: *mut T,
+    storage: S,
     capacity: usize,
-    allocated_size: usize,
+    position_mask: usize,
     read_idx: usize,
     write_idx: usize,
 }
 
-impl RingBuf {
+#[cfg(feature = "alloc")]
+impl<T> RingBuf<T> {
     pub fn with_capacity(capacity: usize) -> Self {
-        let ptr = unsafe { allocate_buffer(capacity) };
+        Self::with_storage(crate::storage::Allocated::heap(capacity), capacity)
+    }
+}
+
+impl<T, S: Storage<T>> RingBuf<T, S> {
+    /// Builds a ring on `storage`. The ring uses the largest power of two slots of it,
+    /// which must be at least `capacity`.
+    pub fn with_storage(mut storage: S, capacity: usize) -> Self {
+        let slots = usable_slots(storage.slots());
+        assert!(
+            0 < slots && capacity <= slots,
+            "storage is smaller than capacity"
+        );
         Self {
-            buf: ptr,
+            buf: storage.as_mut_ptr(),
+            storage,
             capacity,
-            allocated_size: capacity.next_power_of_two(),
+            position_mask: slots - 1,
             read_idx: 0,
             write_idx: 0,
         }
@@ -28,55 +47,189 @@ impl RingBuf {
 
     #[inline]
     fn to_ptr(&self, pos: usize) -> usize {
-        pos & (self.allocated_size - 1)
+        pos & (self.position_mask)
     }
 
     #[inline]
-    unsafe fn load(&self, pos: usize) -> usize {
+    unsafe fn load(&self, pos: usize) -> T {
         let ptr = self.buf.add(pos);
         ptr::read(ptr)
     }
 
     #[inline]
-    unsafe fn store(&self, pos: usize, v: usize) {
+    unsafe fn store(&self, pos: usize, v: T) {
         let ptr = self.buf.add(pos);
         ptr::write(&mut *ptr, v);
     }
+
+    pub fn len(&self) -> usize {
+        // 32bitでは2^32個で一周するので、差はwrapして取る
+        self.write_idx.wrapping_sub(self.read_idx)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Where the storage is allocated.
+    pub fn backing(&self) -> Backing {
+        self.storage.backing()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity
+    }
+
+    pub fn free_slots(&self) -> usize {
+        self.capacity - self.len()
+    }
+
+    /// Moves items out of `iter` until the ring is full or the iterator ends.
+    /// No more items than there are free slots are pulled from the iterator.
+    /// Returns the number of items written.
+    pub fn enqueue_iter<I: IntoIterator<Item = T>>(&mut self, iter: I) -> usize {
+        let mut n = 0;
+        for item in iter.into_iter().take(self.free_slots()) {
+            unsafe {
+                self.store(self.to_ptr(self.write_idx), item);
+            }
+            self.write_idx = self.write_idx.wrapping_add(1);
+            n += 1;
+        }
+        n
+    }
+}
+
+/// Same as [`RingBuf::enqueue_iter`], the items that do not fit are left in the iterator.
+impl<T, S: Storage<T>> Extend<T> for RingBuf<T, S> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.enqueue_iter(iter);
+    }
+}
+
+/// Collects the items into a ring just large enough to hold them.
+#[cfg(feature = "alloc")]
+impl<T> FromIterator<T> for RingBuf<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let items = iter.into_iter().collect::<alloc::vec::Vec<_>>();
+        let mut rb = Self::with_capacity(items.len());
+        rb.enqueue_iter(items);
+        rb
+    }
 }
 
-impl RingBufTrait<usize> for RingBuf {
-    fn enqueue(&mut self, item: usize) -> bool {
-        if (self.write_idx - self.read_idx) == self.capacity {
-            return false;
+/// Dequeues the items in FIFO order.
+impl<T, S: Storage<T>> IntoIterator for RingBuf<T, S> {
+    type Item = T;
+    type IntoIter = IntoIter<T, S>;
+
+    fn into_iter(self) -> IntoIter<T, S> {
+        IntoIter(self)
+    }
+}
+
+#[derive(Debug)]
+pub struct IntoIter<T, S: Storage<T> = DefaultStorage<T>>(RingBuf<T, S>);
+
+impl<T, S: Storage<T>> Iterator for IntoIter<T, S> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.0.dequeue()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl<T, S: Storage<T>> ExactSizeIterator for IntoIter<T, S> {}
+
+impl<T, S: Storage<T>> RingBufTrait<T> for RingBuf<T, S> {
+    fn try_enqueue(&mut self, item: T) -> Result<(), Full<T>> {
+        if self.is_full() {
+            return Err(Full(item));
         }
         unsafe {
             self.store(self.to_ptr(self.write_idx), item);
         }
-        self.write_idx += 1;
-        true
+        self.write_idx = self.write_idx.wrapping_add(1);
+        Ok(())
     }
 
-    fn dequeue(&mut self) -> Option<usize> {
+    fn try_dequeue(&mut self) -> Result<T, Empty> {
         if self.read_idx == self.write_idx {
-            return None;
+            return Err(Empty);
         }
         let item = unsafe { self.load(self.to_ptr(self.read_idx)) };
-        self.read_idx += 1;
-        Some(item)
+        self.read_idx = self.read_idx.wrapping_add(1);
+        Ok(item)
     }
 }
 
-impl Drop for RingBuf {
+impl<T, S: Storage<T>> Drop for RingBuf<T, S> {
     fn drop(&mut self) {
+        // 領域はstorageのdropで解放される
         while self.dequeue().is_some() {}
+    }
+}
 
-        unsafe {
-            let layout = Layout::from_size_align(
-                self.allocated_size * mem::size_of::<usize>(),
-                mem::align_of::<usize>(),
-            )
-            .unwrap();
-            std::alloc::dealloc(self.buf as *mut u8, layout);
+#[cfg(test)]
+mod tests {
+    use super::RingBuf;
+    use crate::RingBufTrait;
+
+    #[test]
+    fn test_iter() {
+        let mut rb = (0..5).collect::<RingBuf<u32>>();
+        assert_eq!((rb.len(), rb.capacity()), (5, 5));
+        assert_eq!(rb.dequeue(), Some(0));
+
+        let mut src = 5..10;
+        assert_eq!(rb.enqueue_iter(&mut src), 1);
+        assert_eq!(src.next(), Some(6));
+        rb.dequeue();
+        rb.extend(src);
+        let iter = rb.into_iter();
+        assert_eq!(iter.len(), 5);
+        assert_eq!(iter.collect::<Vec<_>>(), [2, 3, 4, 5, 7]);
+    }
+
+    #[test]
+    fn test_occupancy() {
+        let mut rb = RingBuf::<u32>::with_capacity(5);
+        assert_eq!(rb.capacity(), 5);
+        assert!(rb.is_empty());
+        for i in 0..5 {
+            assert_eq!(rb.len(), i);
+            assert_eq!(rb.free_slots(), 5 - i);
+            assert!(!rb.is_full());
+            assert!(rb.enqueue(i as u32));
+        }
+        assert!(rb.is_full());
+        assert_eq!(rb.dequeue(), Some(0));
+        assert_eq!(rb.len(), 4);
+        assert_eq!(rb.free_slots(), 1);
+    }
+
+    #[test]
+    fn test_index_wrap() {
+        let mut rb = RingBuf::<u32>::with_capacity(4);
+        rb.read_idx = usize::MAX - 2;
+        rb.write_idx = usize::MAX - 2;
+        for i in 0..10 {
+            assert!(rb.enqueue(i));
+            assert!(rb.enqueue(i + 100));
+            assert_eq!(rb.len(), 2);
+            assert_eq!(rb.dequeue(), Some(i));
+            assert_eq!(rb.dequeue(), Some(i + 100));
         }
+        rb.extend(0..6);
+        assert!(rb.is_full());
+        assert_eq!(rb.into_iter().collect::<Vec<_>>(), [0, 1, 2, 3]);
     }
 }
@@ -1,88 +1,536 @@
 use std::{
-    alloc::Layout,
-    mem, ptr,
-    sync::atomic::{AtomicUsize, Ordering},
+    cell::Cell,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 
-use crate::helper::{allocate_buffer, RingBufTrait};
+use crate::{
+    error::{Empty, Full, TryDequeueError, TryEnqueueError},
+    helper::{
+        dequeue_or_disconnected, Backing, RingBufBatchConsumer, RingBufBatchProducer,
+        RingBufBlockingConsumer, RingBufBlockingProducer, RingBufConsumer, RingBufProducer,
+    },
+    storage::{usable_slots, Allocated, Storage},
+    wait::{BusySpin, WaitStrategy},
+};
 
-pub struct Buffer {
-    buffer: *mut usize,
+pub struct Buffer<T, W: WaitStrategy = BusySpin, S: Storage<T> = Allocated<T>> {
+    buffer: *mut T,
     capacity: usize,
-    allocated_size: usize,
+    position_mask: usize,
+    // 片側のハンドルがdropされたことを相手側に伝える
+    producer_closed: AtomicBool,
+    consumer_closed: AtomicBool,
     write_idx: AtomicUsize,
     read_idx: AtomicUsize,
+    wait: W,
+    storage: S,
+}
+unsafe impl<T: Sync, W: WaitStrategy, S: Storage<T> + Sync> Sync for Buffer<T, W, S> {}
+
+pub struct Consumer<T, W: WaitStrategy = BusySpin, S: Storage<T> = Allocated<T>> {
+    buffer: Arc<Buffer<T, W, S>>,
+    // Drainで読み出し済みだがread_idxに未反映の個数
+    pending: Cell<usize>,
 }
 
-impl Buffer {
+pub struct Producer<T, W: WaitStrategy = BusySpin, S: Storage<T> = Allocated<T>> {
+    buffer: Arc<Buffer<T, W, S>>,
+}
+
+unsafe impl<T: Send, W: WaitStrategy + Send, S: Storage<T> + Send> Send for Consumer<T, W, S> {}
+unsafe impl<T: Send, W: WaitStrategy + Send, S: Storage<T> + Send> Send for Producer<T, W, S> {}
+
+impl<T> Buffer<T> {
     pub fn with_capacity(capacity: usize) -> Self {
-        let ptr = unsafe { allocate_buffer(capacity) };
+        Self::with_capacity_and_wait(capacity, BusySpin)
+    }
+}
+
+impl<T, W: WaitStrategy> Buffer<T, W> {
+    pub fn with_capacity_and_wait(capacity: usize, wait: W) -> Self {
+        Self::with_storage_and_wait(Allocated::heap(capacity), capacity, wait)
+    }
+
+    /// Same as [`Buffer::with_capacity_and_wait`] but the storage is on huge pages when
+    /// possible, check which one it got with [`Buffer::backing`].
+    pub fn huge_with_capacity_and_wait(capacity: usize, wait: W) -> Self {
+        Self::with_storage_and_wait(Allocated::huge(capacity), capacity, wait)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Buffer<T, W, S> {
+    /// Builds a ring on `storage`. The ring uses the largest power of two slots of it,
+    /// which must be at least `capacity`.
+    pub fn with_storage_and_wait(mut storage: S, capacity: usize, wait: W) -> Self {
+        let slots = usable_slots(storage.slots());
+        assert!(
+            0 < slots && capacity <= slots,
+            "storage is smaller than capacity"
+        );
         Self {
-            buffer: ptr,
+            buffer: storage.as_mut_ptr(),
             capacity,
-            allocated_size: capacity.next_power_of_two(),
+            position_mask: slots - 1,
+            producer_closed: AtomicBool::new(false),
+            consumer_closed: AtomicBool::new(false),
             write_idx: AtomicUsize::new(0),
             read_idx: AtomicUsize::new(0),
+            wait,
+            storage,
         }
     }
 
+    /// Where the storage is allocated.
+    pub fn backing(&self) -> Backing {
+        self.storage.backing()
+    }
+
+    #[inline]
+    fn publish_write(&self, write_idx: usize) {
+        self.write_idx.store(write_idx, Ordering::Release);
+        self.wait.notify();
+    }
+
+    #[inline]
+    fn publish_read(&self, read_idx: usize) {
+        self.read_idx.store(read_idx, Ordering::Release);
+        self.wait.notify();
+    }
+
     #[inline]
     fn buf_offset(&self, idx: usize) -> usize {
-        idx & (self.allocated_size - 1)
+        idx & (self.position_mask)
     }
 
     #[inline]
-    unsafe fn load(&self, pos: usize) -> usize {
+    unsafe fn slot(&self, pos: usize) -> *mut T {
+        self.buffer.add(self.buf_offset(pos))
+    }
+
+    #[inline]
+    unsafe fn load(&self, pos: usize) -> T {
         let end = self.buffer.add(self.buf_offset(pos));
         ptr::read(end)
     }
 
     #[inline]
-    unsafe fn store(&self, pos: usize, v: usize) {
+    unsafe fn store(&self, pos: usize, v: T) {
         let end = self.buffer.add(self.buf_offset(pos));
         ptr::write(&mut *end, v);
     }
-}
 
-impl Drop for Buffer {
-    fn drop(&mut self) {
-        unsafe {
-            let layout = Layout::from_size_align(
-                self.allocated_size * mem::size_of::<usize>(),
-                mem::align_of::<usize>(),
-            )
-            .unwrap();
-            std::alloc::dealloc(self.buffer as *mut u8, layout);
-        }
+    /// Copies `len` items from `src` into the slots starting at `pos`.
+    /// The run is split in two when it crosses the end of the buffer.
+    #[inline]
+    unsafe fn write_run(&self, pos: usize, src: *const T, len: usize) {
+        let offset = self.buf_offset(pos);
+        let first = len.min(self.position_mask + 1 - offset);
+        ptr::copy_nonoverlapping(src, self.buffer.add(offset), first);
+        ptr::copy_nonoverlapping(src.add(first), self.buffer, len - first);
+    }
+
+    /// Moves `len` items from the slots starting at `pos` into `dst`.
+    #[inline]
+    unsafe fn read_run(&self, pos: usize, dst: *mut T, len: usize) {
+        let offset = self.buf_offset(pos);
+        let first = len.min(self.position_mask + 1 - offset);
+        ptr::copy_nonoverlapping(self.buffer.add(offset), dst, first);
+        ptr::copy_nonoverlapping(self.buffer, dst.add(first), len - first);
+    }
+
+    #[inline]
+    fn writable(&self, write_idx: usize) -> usize {
+        let read_idx = self.read_idx.load(Ordering::Acquire);
+        self.capacity - write_idx.wrapping_sub(read_idx)
+    }
+
+    #[inline]
+    fn readable(&self, read_idx: usize) -> usize {
+        self.write_idx
+            .load(Ordering::Acquire)
+            .wrapping_sub(read_idx)
+    }
+
+    #[inline]
+    pub(crate) fn dequeue(&self) -> Option<T> {
+        self.try_dequeue().ok()
     }
-}
 
-impl RingBufTrait<usize> for Buffer {
-    fn enqueue(&mut self, item: usize) -> bool {
+    pub(crate) fn try_enqueue(&self, item: T) -> Result<(), Full<T>> {
         let write_idx = self.write_idx.load(Ordering::Relaxed);
         let read_idx = self.read_idx.load(Ordering::Acquire);
-        if read_idx + self.capacity <= write_idx {
-            return false;
+        if write_idx.wrapping_sub(read_idx) >= self.capacity {
+            return Err(Full(item));
         }
 
         unsafe {
             self.store(write_idx, item);
         }
-        self.write_idx
-            .store(write_idx.wrapping_add(1), Ordering::Release);
-        true
+        self.publish_write(write_idx.wrapping_add(1));
+        Ok(())
     }
 
-    fn dequeue(&mut self) -> Option<usize> {
+    pub(crate) fn try_dequeue(&self) -> Result<T, Empty> {
         let read_idx = self.read_idx.load(Ordering::Relaxed);
         let write_idx = self.write_idx.load(Ordering::Acquire);
         if write_idx == read_idx {
-            return None;
+            return Err(Empty);
         }
 
         let v = unsafe { self.load(read_idx) };
-        self.read_idx
-            .store(read_idx.wrapping_add(1), Ordering::Release);
+        self.publish_read(read_idx.wrapping_add(1));
+        Ok(v)
+    }
+
+    pub(crate) fn enqueue_slice(&self, items: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        let write_idx = self.write_idx.load(Ordering::Relaxed);
+        let n = items.len().min(self.writable(write_idx));
+        if n == 0 {
+            return 0;
+        }
+
+        unsafe {
+            self.write_run(write_idx, items.as_ptr(), n);
+        }
+        self.publish_write(write_idx.wrapping_add(n));
+        n
+    }
+
+    pub(crate) fn enqueue_iter<I: IntoIterator<Item = T>>(&self, iter: I) -> usize {
+        let write_idx = self.write_idx.load(Ordering::Relaxed);
+        let free = self.writable(write_idx);
+        let mut n = 0;
+        for item in iter.into_iter().take(free) {
+            unsafe {
+                self.store(write_idx.wrapping_add(n), item);
+            }
+            n += 1;
+        }
+        if n == 0 {
+            return 0;
+        }
+
+        self.publish_write(write_idx.wrapping_add(n));
+        n
+    }
+
+    pub(crate) fn dequeue_into(&self, dst: &mut [MaybeUninit<T>]) -> usize {
+        let read_idx = self.read_idx.load(Ordering::Relaxed);
+        let n = dst.len().min(self.readable(read_idx));
+        if n == 0 {
+            return 0;
+        }
+
+        unsafe {
+            self.read_run(read_idx, dst.as_mut_ptr() as *mut T, n);
+        }
+        self.publish_read(read_idx.wrapping_add(n));
+        n
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Drop for Buffer<T, W, S> {
+    fn drop(&mut self) {
+        // 領域はstorageのdropで解放される
+        while self.dequeue().is_some() {}
+    }
+}
+
+pub fn make<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    make_with_wait(capacity, BusySpin)
+}
+
+/// The producer side of `FromIterator`: a ring that starts with every item of `iter`.
+/// The capacity is the number of items, at least 1, so all of them are accepted.
+pub fn make_from_iter<T, I: IntoIterator<Item = T>>(iter: I) -> (Producer<T>, Consumer<T>) {
+    let items = iter.into_iter().collect::<Vec<_>>();
+    let (p, c) = make(items.len().max(1));
+    let n = p.enqueue_iter(items);
+    debug_assert_eq!(n, c.len());
+    (p, c)
+}
+
+/// Same as [`make`] but the blocking operations of the handles wait with `wait`.
+pub fn make_with_wait<T, W: WaitStrategy>(
+    capacity: usize,
+    wait: W,
+) -> (Producer<T, W>, Consumer<T, W>) {
+    from_buffer(Buffer::with_capacity_and_wait(capacity, wait))
+}
+
+/// Same as [`make`] but the storage is on huge pages when possible,
+/// see [`Buffer::huge_with_capacity_and_wait`].
+pub fn make_huge<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    from_buffer(Buffer::huge_with_capacity_and_wait(capacity, BusySpin))
+}
+
+/// Splits a buffer built by one of the `Buffer` constructors into the handles.
+/// The buffer is reachable only through them, so there is a single producer and consumer.
+pub fn from_buffer<T, W: WaitStrategy, S: Storage<T>>(
+    buffer: Buffer<T, W, S>,
+) -> (Producer<T, W, S>, Consumer<T, W, S>) {
+    let arc = Arc::new(buffer);
+
+    (
+        Producer {
+            buffer: arc.clone(),
+        },
+        Consumer {
+            buffer: arc,
+            pending: Cell::new(0),
+        },
+    )
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufProducer<T> for Producer<T, W, S> {
+    fn try_enqueue(&self, item: T) -> Result<(), TryEnqueueError<T>> {
+        if self.is_closed() {
+            return Err(TryEnqueueError::Disconnected(item));
+        }
+        (*self.buffer).try_enqueue(item).map_err(Into::into)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufConsumer<T> for Consumer<T, W, S> {
+    fn try_dequeue(&self) -> Result<T, TryDequeueError> {
+        self.release_pending();
+        dequeue_or_disconnected(|| (*self.buffer).try_dequeue(), || self.is_closed())
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufBlockingProducer<T> for Producer<T, W, S> {
+    fn is_closed(&self) -> bool {
+        self.buffer.consumer_closed.load(Ordering::Acquire)
+            || self.buffer.producer_closed.load(Ordering::Relaxed)
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    fn free_slots(&self) -> usize {
+        let write_idx = self.buffer.write_idx.load(Ordering::Relaxed);
+        self.buffer.writable(write_idx)
+    }
+
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool {
+        self.buffer.wait.wait_until(ready, deadline)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufBlockingConsumer<T> for Consumer<T, W, S> {
+    fn is_closed(&self) -> bool {
+        self.buffer.producer_closed.load(Ordering::Acquire)
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    fn len(&self) -> usize {
+        self.release_pending();
+        let read_idx = self.buffer.read_idx.load(Ordering::Relaxed);
+        self.buffer.readable(read_idx)
+    }
+
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool {
+        self.buffer.wait.wait_until(ready, deadline)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufBatchProducer<T> for Producer<T, W, S> {
+    fn enqueue_slice(&self, items: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        if self.is_closed() {
+            return 0;
+        }
+        (*self.buffer).enqueue_slice(items)
+    }
+
+    fn enqueue_iter<I: IntoIterator<Item = T>>(&self, iter: I) -> usize {
+        if self.is_closed() {
+            return 0;
+        }
+        (*self.buffer).enqueue_iter(iter)
+    }
+}
+
+/// Enqueues items until the ring is full, then stops pulling from the iterator.
+///
+/// `extend` can not report a count, so the items that do not fit are **not** enqueued: they
+/// stay in the iterator when it is passed as `&mut`, otherwise they are dropped with it.
+/// Use [`RingBufBatchProducer::enqueue_iter`] to get the number of accepted items.
+impl<T, W: WaitStrategy, S: Storage<T>> Extend<T> for Producer<T, W, S> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.enqueue_iter(iter);
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufBatchConsumer<T> for Consumer<T, W, S> {
+    fn dequeue_into(&self, dst: &mut [MaybeUninit<T>]) -> usize {
+        self.release_pending();
+        (*self.buffer).dequeue_into(dst)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Producer<T, W, S> {
+    /// Marks the ring as closed. The consumer gets `Disconnected` once the remaining items are
+    /// drained, and further enqueues from this handle fail with `Disconnected`.
+    /// Dropping the producer closes the ring as well.
+    pub fn close(&self) {
+        self.buffer.producer_closed.store(true, Ordering::Release);
+        self.buffer.wait.notify();
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Consumer<T, W, S> {
+    /// Returns a reference to the next item without dequeuing it.
+    pub fn peek(&mut self) -> Option<&T> {
+        self.peek_nth(0)
+    }
+
+    /// Returns a reference to the `i`-th readable item (0 is the next one) without dequeuing it.
+    pub fn peek_nth(&mut self, i: usize) -> Option<&T> {
+        self.release_pending();
+        let read_idx = self.buffer.read_idx.load(Ordering::Relaxed);
+        if i < self.buffer.readable(read_idx) {
+            Some(unsafe { &*self.buffer.slot(read_idx.wrapping_add(i)) })
+        } else {
+            None
+        }
+    }
+
+    /// Returns an iterator that moves out up to `max` items which are readable now.
+    /// The read index is published once when the iterator is dropped.
+    pub fn drain(&mut self, max: usize) -> Drain<'_, T, W, S> {
+        self.release_pending();
+        let start = self.buffer.read_idx.load(Ordering::Relaxed);
+        let remaining = max.min(self.buffer.readable(start));
+        Drain {
+            consumer: self,
+            start,
+            remaining,
+        }
+    }
+
+    #[inline]
+    fn release_pending(&self) {
+        let pending = self.pending.get();
+        if pending != 0 {
+            let read_idx = self.buffer.read_idx.load(Ordering::Relaxed);
+            self.buffer.publish_read(read_idx.wrapping_add(pending));
+            self.pending.set(0);
+        }
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Drop for Consumer<T, W, S> {
+    fn drop(&mut self) {
+        self.release_pending();
+        self.buffer.consumer_closed.store(true, Ordering::Release);
+        self.buffer.wait.notify();
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Drop for Producer<T, W, S> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+pub struct Drain<'a, T, W: WaitStrategy = BusySpin, S: Storage<T> = Allocated<T>> {
+    consumer: &'a mut Consumer<T, W, S>,
+    start: usize,
+    remaining: usize,
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Iterator for Drain<'_, T, W, S> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        // 読み出し済みの個数はConsumer側に記録するので、Drainがleakしても二重に読まれない
+        let pending = self.consumer.pending.get();
+        let v = unsafe { self.consumer.buffer.load(self.start.wrapping_add(pending)) };
+        self.consumer.pending.set(pending + 1);
+        self.remaining -= 1;
         Some(v)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> ExactSizeIterator for Drain<'_, T, W, S> {}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Drop for Drain<'_, T, W, S> {
+    fn drop(&mut self) {
+        self.consumer.release_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use crate::{RingBufConsumer, RingBufProducer};
+
+    #[test]
+    fn test_index_wrap() {
+        let (p, c) = super::make::<u32>(4);
+        // usize::MAXをまたいでも満杯の判定がずれない
+        p.buffer.write_idx.store(usize::MAX - 2, Ordering::Relaxed);
+        p.buffer.read_idx.store(usize::MAX - 2, Ordering::Relaxed);
+        for round in 0..3 {
+            for i in 0..4 {
+                assert!(p.enqueue(i + round));
+            }
+            assert!(!p.enqueue(99));
+            for i in 0..4 {
+                assert_eq!(c.dequeue(), Some(i + round));
+            }
+            assert_eq!(c.dequeue(), None);
+        }
+    }
+
+    #[test]
+    fn test_peek() {
+        let (p, mut c) = super::make::<u32>(5);
+        for i in 0..5 {
+            assert!(p.enqueue(i));
+        }
+        assert_eq!(c.peek(), Some(&0));
+        assert_eq!(c.peek_nth(4), Some(&4));
+        assert_eq!(c.peek_nth(5), None);
+        assert_eq!(c.peek_nth(usize::MAX), None);
+        assert_eq!(c.dequeue(), Some(0));
+        assert_eq!(c.peek(), Some(&1));
+        while c.dequeue().is_some() {}
+        assert_eq!(c.peek(), None);
+    }
+
+    #[test]
+    fn test_huge() {
+        // どのbackingになるかは環境次第。末尾を跨いでもheapと同じように動く
+        let (p, c) = super::make_huge::<u64>(5);
+        assert_ne!(p.buffer.backing(), crate::Backing::Mirrored);
+        for i in 0..20 {
+            assert!(p.enqueue(i));
+            assert_eq!(c.dequeue(), Some(i));
+        }
+    }
 }
@@ -1,106 +1,1426 @@
 use std::{
-    alloc::Layout,
     cell::Cell,
-    mem, ptr,
-    sync::atomic::{AtomicUsize, Ordering},
+    future::Future,
+    io,
+    mem::{self, MaybeUninit},
+    pin::Pin,
+    ptr, slice,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Instant,
 };
 
-use crate::helper::{allocate_buffer, RingBufTrait};
+use crate::{
+    error::{Disconnected, Empty, Full, TryDequeueError, TryEnqueueError},
+    helper::{
+        dequeue_or_disconnected, Backing, CachePadded, RingBufBatchConsumer, RingBufBatchProducer,
+        RingBufBlockingConsumer, RingBufBlockingProducer, RingBufConsumer, RingBufProducer,
+    },
+    storage::{usable_slots, Allocated, Storage},
+    wait::{AsyncWake, BusySpin, WaitStrategy},
+};
 
-pub struct Buffer {
-    buffer: *mut usize,
+#[repr(C)]
+pub struct Buffer<T, W: WaitStrategy = BusySpin, S: Storage<T> = Allocated<T>> {
+    buffer: *mut T,
     capacity: usize,
-    allocated_size: usize,
-    // PCで別のスレッドが触るので個別のL1キャッシュに乗るようにPaddingで埋めて分割する
-    _padding0: [usize; crate::cacheline_pad!(3)],
+    position_mask: usize,
+    // 片側のハンドルがdropされたことを相手側に伝える
+    producer_closed: AtomicBool,
+    consumer_closed: AtomicBool,
+    // Mirroredなら末尾を跨ぐ範囲も連続している
+    backing: Backing,
+    // PCで別のスレッドが触るので個別のcachelineに乗るようにalignして分割する
+    // Bufferごとalignされるので、Arcの中でも行の先頭から始まる
+    producer: CachePadded<ProducerSide>,
+    consumer: CachePadded<ConsumerSide>,
+    wait: W,
+    storage: S,
+}
+unsafe impl<T: Sync, W: WaitStrategy, S: Storage<T> + Sync> Sync for Buffer<T, W, S> {}
+
+/// Written by the producer, read by the consumer only to refresh its cache.
+#[derive(Default)]
+struct ProducerSide {
     write_idx: AtomicUsize,
     cached_read_idx: Cell<usize>,
-    _padding1: [usize; crate::cacheline_pad!(2)],
+    write_laps: Laps,
+}
+
+#[derive(Default)]
+struct ConsumerSide {
     read_idx: AtomicUsize,
     cached_write_idx: Cell<usize>,
+    read_laps: Laps,
+}
+
+/// Extends an index to the 64-bit sequence number of the slot.
+/// On 64-bit targets the index itself is the sequence number,
+/// on narrower ones the times the index wrapped around is counted on the side that owns it.
+#[derive(Default)]
+struct Laps(#[cfg(not(target_pointer_width = "64"))] Cell<u32>);
+
+#[cfg(target_pointer_width = "64")]
+impl Laps {
+    /// Called by the owner of `idx` before storing `next` to it.
+    #[inline]
+    fn advance(&self, _idx: &AtomicUsize, _next: usize) {}
+
+    #[inline]
+    fn seq(&self, idx: usize) -> u64 {
+        idx as u64
+    }
+}
+
+#[cfg(not(target_pointer_width = "64"))]
+impl Laps {
+    #[inline]
+    fn advance(&self, idx: &AtomicUsize, next: usize) {
+        // 1回のpublishで進むのはcapacity以下なので、小さくなったら一周している
+        if next < idx.load(Ordering::Relaxed) {
+            self.0.set(self.0.get().wrapping_add(1));
+        }
+    }
+
+    #[inline]
+    fn seq(&self, idx: usize) -> u64 {
+        ((self.0.get() as u64) << usize::BITS) | idx as u64
+    }
+}
+
+pub struct Consumer<T, W: WaitStrategy = BusySpin, S: Storage<T> = Allocated<T>> {
+    buffer: Arc<Buffer<T, W, S>>,
+    // 読み出し済みだがread_idxに未反映の個数
+    pending: Cell<usize>,
+    publish_interval: usize,
+}
+
+pub struct Producer<T, W: WaitStrategy = BusySpin, S: Storage<T> = Allocated<T>> {
+    buffer: Arc<Buffer<T, W, S>>,
+    // 書き込み済みだがwrite_idxに未反映の個数
+    pending: Cell<usize>,
+    publish_interval: usize,
 }
 
-impl Buffer {
+unsafe impl<T: Send, W: WaitStrategy + Send, S: Storage<T> + Send> Send for Consumer<T, W, S> {}
+unsafe impl<T: Send, W: WaitStrategy + Send, S: Storage<T> + Send> Send for Producer<T, W, S> {}
+
+impl<T> Buffer<T> {
     pub fn with_capacity(capacity: usize) -> Self {
-        let ptr = unsafe { allocate_buffer(capacity) };
+        Self::with_capacity_and_wait(capacity, BusySpin)
+    }
+}
+
+impl<T, W: WaitStrategy> Buffer<T, W> {
+    pub fn with_capacity_and_wait(capacity: usize, wait: W) -> Self {
+        Self::with_storage_and_wait(Allocated::heap(capacity), capacity, wait)
+    }
+
+    /// Same as [`Buffer::with_capacity_and_wait`] but the storage is on huge pages when
+    /// possible, check which one it got with [`Buffer::backing`].
+    pub fn huge_with_capacity_and_wait(capacity: usize, wait: W) -> Self {
+        Self::with_storage_and_wait(Allocated::huge(capacity), capacity, wait)
+    }
+
+    /// Same as [`Buffer::with_capacity_and_wait`] but the storage is mapped twice with
+    /// [`crate::mirror`], so `reserve` and `read_chunk` always return a single segment.
+    /// Falls back to the heap when the mapping fails, check it with [`Buffer::backing`].
+    pub fn mirrored_with_capacity_and_wait(capacity: usize, wait: W) -> Self {
+        let storage = Allocated::mirrored(capacity).unwrap_or_else(|| Allocated::heap(capacity));
+        Self::with_storage_and_wait(storage, capacity, wait)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Buffer<T, W, S> {
+    /// Builds a ring on `storage`. The ring uses the largest power of two slots of it,
+    /// which must be at least `capacity`. A mirrored storage must have a power of two slots.
+    pub fn with_storage_and_wait(mut storage: S, capacity: usize, wait: W) -> Self {
+        let slots = usable_slots(storage.slots());
+        assert!(
+            0 < slots && capacity <= slots,
+            "storage is smaller than capacity"
+        );
+        // 切り下げて使うとringの周回とミラーの境界がずれる
+        assert!(
+            storage.backing() != Backing::Mirrored || slots == storage.slots(),
+            "mirrored storage must have a power of two slots"
+        );
         Self {
-            buffer: ptr,
+            buffer: storage.as_mut_ptr(),
             capacity,
-            allocated_size: capacity.next_power_of_two(),
-            _padding0: [0; crate::cacheline_pad!(3)],
-            write_idx: AtomicUsize::new(0),
-            cached_read_idx: Cell::new(0),
-            _padding1: [0; crate::cacheline_pad!(2)],
-            read_idx: AtomicUsize::new(0),
-            cached_write_idx: Cell::new(0),
+            position_mask: slots - 1,
+            producer_closed: AtomicBool::new(false),
+            consumer_closed: AtomicBool::new(false),
+            backing: storage.backing(),
+            producer: CachePadded::default(),
+            consumer: CachePadded::default(),
+            wait,
+            storage,
         }
     }
 
+    #[inline]
+    fn publish_write(&self, write_idx: usize) {
+        self.producer
+            .write_laps
+            .advance(&self.producer.write_idx, write_idx);
+        self.producer.write_idx.store(write_idx, Ordering::Release);
+        self.wait.notify();
+    }
+
+    #[inline]
+    fn publish_read(&self, read_idx: usize) {
+        self.consumer
+            .read_laps
+            .advance(&self.consumer.read_idx, read_idx);
+        self.consumer.read_idx.store(read_idx, Ordering::Release);
+        self.wait.notify();
+    }
+
     #[inline]
     fn buf_offset(&self, idx: usize) -> usize {
-        idx & (self.allocated_size - 1)
+        idx & (self.position_mask)
     }
 
     #[inline]
-    unsafe fn load(&self, pos: usize) -> usize {
+    unsafe fn slot(&self, pos: usize) -> *mut T {
+        self.buffer.add(self.buf_offset(pos))
+    }
+
+    #[inline]
+    unsafe fn load(&self, pos: usize) -> T {
         let end = self.buffer.add(self.buf_offset(pos));
         ptr::read(end)
     }
 
     #[inline]
-    unsafe fn store(&self, pos: usize, v: usize) {
+    unsafe fn store(&self, pos: usize, v: T) {
         let end = self.buffer.add(self.buf_offset(pos));
         ptr::write(&mut *end, v);
     }
+
+    /// Copies `len` items from `src` into the slots starting at `pos`.
+    /// The run is split in two when it crosses the end of the buffer.
+    #[inline]
+    unsafe fn write_run(&self, pos: usize, src: *const T, len: usize) {
+        let (head, first, second) = self.segments(pos, len);
+        ptr::copy_nonoverlapping(src, head, first);
+        ptr::copy_nonoverlapping(src.add(first), self.buffer, second);
+    }
+
+    /// Moves `len` items from the slots starting at `pos` into `dst`.
+    #[inline]
+    unsafe fn read_run(&self, pos: usize, dst: *mut T, len: usize) {
+        let (head, first, second) = self.segments(pos, len);
+        ptr::copy_nonoverlapping(head, dst, first);
+        ptr::copy_nonoverlapping(self.buffer, dst.add(first), second);
+    }
+
+    /// Splits the run of `len` slots starting at `pos` at the end of the buffer.
+    /// Returns the head pointer and the length of both segments, the second one starts at slot 0.
+    /// The second one is always empty when the buffer is mirrored.
+    #[inline]
+    fn segments(&self, pos: usize, len: usize) -> (*mut T, usize, usize) {
+        let offset = self.buf_offset(pos);
+        let first = if self.backing == Backing::Mirrored {
+            len
+        } else {
+            len.min(self.position_mask + 1 - offset)
+        };
+        (unsafe { self.buffer.add(offset) }, first, len - first)
+    }
+
+    /// Where the storage is allocated.
+    pub fn backing(&self) -> Backing {
+        self.backing
+    }
+
+    /// Number of free slots seen from the producer.
+    /// `read_idx` is loaded only when the cached value can not satisfy `want`.
+    #[inline]
+    fn writable(&self, write_idx: usize, want: usize) -> usize {
+        let mut free = self.capacity - write_idx.wrapping_sub(self.producer.cached_read_idx.get());
+        if free < want {
+            self.producer
+                .cached_read_idx
+                .set(self.consumer.read_idx.load(Ordering::Acquire));
+            free = self.capacity - write_idx.wrapping_sub(self.producer.cached_read_idx.get());
+        }
+        free
+    }
+
+    /// Number of readable items seen from the consumer.
+    /// `write_idx` is loaded only when the cached value can not satisfy `want`.
+    #[inline]
+    fn readable(&self, read_idx: usize, want: usize) -> usize {
+        let mut avail = self.consumer.cached_write_idx.get().wrapping_sub(read_idx);
+        if avail < want {
+            self.consumer
+                .cached_write_idx
+                .set(self.producer.write_idx.load(Ordering::Acquire));
+            avail = self.consumer.cached_write_idx.get().wrapping_sub(read_idx);
+        }
+        avail
+    }
+
+    #[inline]
+    pub(crate) fn dequeue(&self) -> Option<T> {
+        self.try_dequeue().ok()
+    }
+
+    pub(crate) fn try_enqueue(&self, item: T) -> Result<(), Full<T>> {
+        let write_idx = self.producer.write_idx.load(Ordering::Relaxed);
+        // 32bitではindexが一周するので差分で比べる
+        if self.writable(write_idx, 1) == 0 {
+            return Err(Full(item));
+        }
+
+        unsafe {
+            self.store(write_idx, item);
+        }
+        self.publish_write(write_idx.wrapping_add(1));
+        Ok(())
+    }
+
+    pub(crate) fn try_dequeue(&self) -> Result<T, Empty> {
+        let read_idx = self.consumer.read_idx.load(Ordering::Relaxed);
+        if self.readable(read_idx, 1) == 0 {
+            return Err(Empty);
+        }
+
+        let v = unsafe { self.load(read_idx) };
+        self.publish_read(read_idx.wrapping_add(1));
+        Ok(v)
+    }
+
+    pub(crate) fn enqueue_slice(&self, items: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        let write_idx = self.producer.write_idx.load(Ordering::Relaxed);
+        let n = items.len().min(self.writable(write_idx, items.len()));
+        if n == 0 {
+            return 0;
+        }
+
+        unsafe {
+            self.write_run(write_idx, items.as_ptr(), n);
+        }
+        self.publish_write(write_idx.wrapping_add(n));
+        n
+    }
+
+    pub(crate) fn enqueue_iter<I: IntoIterator<Item = T>>(&self, iter: I) -> usize {
+        let write_idx = self.producer.write_idx.load(Ordering::Relaxed);
+        let free = self.writable(write_idx, usize::MAX);
+        let mut n = 0;
+        for item in iter.into_iter().take(free) {
+            unsafe {
+                self.store(write_idx.wrapping_add(n), item);
+            }
+            n += 1;
+        }
+        if n == 0 {
+            return 0;
+        }
+
+        self.publish_write(write_idx.wrapping_add(n));
+        n
+    }
+
+    pub(crate) fn dequeue_into(&self, dst: &mut [MaybeUninit<T>]) -> usize {
+        let read_idx = self.consumer.read_idx.load(Ordering::Relaxed);
+        let n = dst.len().min(self.readable(read_idx, dst.len()));
+        if n == 0 {
+            return 0;
+        }
+
+        unsafe {
+            self.read_run(read_idx, dst.as_mut_ptr() as *mut T, n);
+        }
+        self.publish_read(read_idx.wrapping_add(n));
+        n
+    }
 }
 
-impl Drop for Buffer {
+impl<T, W: WaitStrategy, S: Storage<T>> Drop for Buffer<T, W, S> {
     fn drop(&mut self) {
+        // 領域はstorageのdropで解放される
+        while self.dequeue().is_some() {}
+    }
+}
+
+pub fn make<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    make_with_wait(capacity, BusySpin)
+}
+
+/// The producer side of `FromIterator`: a ring that starts with every item of `iter`.
+/// The capacity is the number of items, at least 1, so all of them are accepted.
+pub fn make_from_iter<T, I: IntoIterator<Item = T>>(iter: I) -> (Producer<T>, Consumer<T>) {
+    let items = iter.into_iter().collect::<Vec<_>>();
+    let (p, c) = make(items.len().max(1));
+    let n = p.enqueue_iter(items);
+    debug_assert_eq!(n, c.len());
+    (p, c)
+}
+
+/// Same as [`make`] but the blocking operations of the handles wait with `wait`.
+pub fn make_with_wait<T, W: WaitStrategy>(
+    capacity: usize,
+    wait: W,
+) -> (Producer<T, W>, Consumer<T, W>) {
+    from_buffer(Buffer::with_capacity_and_wait(capacity, wait))
+}
+
+/// Same as [`make`] but the storage is mapped twice when possible,
+/// see [`Buffer::mirrored_with_capacity_and_wait`].
+pub fn make_mirrored<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    from_buffer(Buffer::mirrored_with_capacity_and_wait(capacity, BusySpin))
+}
+
+/// Same as [`make`] but the storage is on huge pages when possible,
+/// see [`Buffer::huge_with_capacity_and_wait`].
+pub fn make_huge<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    from_buffer(Buffer::huge_with_capacity_and_wait(capacity, BusySpin))
+}
+
+/// Splits a buffer built by one of the `Buffer` constructors into the handles.
+/// The buffer is reachable only through them, so there is a single producer and consumer.
+pub fn from_buffer<T, W: WaitStrategy, S: Storage<T>>(
+    buffer: Buffer<T, W, S>,
+) -> (Producer<T, W, S>, Consumer<T, W, S>) {
+    let arc = Arc::new(buffer);
+
+    (
+        Producer {
+            buffer: arc.clone(),
+            pending: Cell::new(0),
+            publish_interval: 1,
+        },
+        Consumer {
+            buffer: arc,
+            pending: Cell::new(0),
+            publish_interval: 1,
+        },
+    )
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufProducer<T> for Producer<T, W, S> {
+    fn try_enqueue(&self, item: T) -> Result<(), TryEnqueueError<T>> {
+        if self.is_closed() {
+            return Err(TryEnqueueError::Disconnected(item));
+        }
+        if self.publish_interval == 1 {
+            return (*self.buffer).try_enqueue(item).map_err(Into::into);
+        }
+        self.enqueue_deferred(item).map_err(Into::into)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufConsumer<T> for Consumer<T, W, S> {
+    fn try_dequeue(&self) -> Result<T, TryDequeueError> {
+        dequeue_or_disconnected(|| self.dequeue_deferred(), || self.is_closed())
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufBlockingProducer<T> for Producer<T, W, S> {
+    fn is_closed(&self) -> bool {
+        self.buffer.consumer_closed.load(Ordering::Acquire)
+            || self.buffer.producer_closed.load(Ordering::Relaxed)
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    fn free_slots(&self) -> usize {
+        self.publish_pending();
+        let write_idx = self.buffer.producer.write_idx.load(Ordering::Relaxed);
+        self.buffer.writable(write_idx, usize::MAX)
+    }
+
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool {
+        self.buffer.wait.wait_until(ready, deadline)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufBlockingConsumer<T> for Consumer<T, W, S> {
+    fn is_closed(&self) -> bool {
+        self.buffer.producer_closed.load(Ordering::Acquire)
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    fn len(&self) -> usize {
+        self.release_pending();
+        let read_idx = self.buffer.consumer.read_idx.load(Ordering::Relaxed);
+        self.buffer.readable(read_idx, usize::MAX)
+    }
+
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool {
+        self.buffer.wait.wait_until(ready, deadline)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufBatchProducer<T> for Producer<T, W, S> {
+    fn enqueue_slice(&self, items: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        if self.is_closed() {
+            return 0;
+        }
+        self.publish_pending();
+        (*self.buffer).enqueue_slice(items)
+    }
+
+    fn enqueue_iter<I: IntoIterator<Item = T>>(&self, iter: I) -> usize {
+        if self.is_closed() {
+            return 0;
+        }
+        self.publish_pending();
+        (*self.buffer).enqueue_iter(iter)
+    }
+}
+
+/// Enqueues items until the ring is full, then stops pulling from the iterator.
+///
+/// `extend` can not report a count, so the items that do not fit are **not** enqueued: they
+/// stay in the iterator when it is passed as `&mut`, otherwise they are dropped with it.
+/// Use [`RingBufBatchProducer::enqueue_iter`] to get the number of accepted items.
+impl<T, W: WaitStrategy, S: Storage<T>> Extend<T> for Producer<T, W, S> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.enqueue_iter(iter);
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> RingBufBatchConsumer<T> for Consumer<T, W, S> {
+    fn dequeue_into(&self, dst: &mut [MaybeUninit<T>]) -> usize {
+        self.release_pending();
+        (*self.buffer).dequeue_into(dst)
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Producer<T, W, S> {
+    /// Marks the ring as closed. The consumer gets `Disconnected` once the remaining items are
+    /// drained, and further enqueues from this handle fail with `Disconnected`.
+    /// Dropping the producer closes the ring as well.
+    pub fn close(&self) {
+        self.publish_pending();
+        self.buffer.producer_closed.store(true, Ordering::Release);
+        self.buffer.wait.notify();
+    }
+
+    /// Sequence number the next enqueued item gets. Starts at 0 and counts every item
+    /// published by this producer, so it does not wrap even on 32-bit targets.
+    pub fn next_seq(&self) -> u64 {
+        let buffer = &*self.buffer;
+        let published = buffer
+            .producer
+            .write_laps
+            .seq(buffer.producer.write_idx.load(Ordering::Relaxed));
+        published + self.pending.get() as u64
+    }
+
+    /// Same as [`RingBufProducer::try_enqueue`] but returns the sequence number of the item.
+    pub fn try_enqueue_with_seq(&self, item: T) -> Result<u64, TryEnqueueError<T>> {
+        let seq = self.next_seq();
+        self.try_enqueue(item).map(|()| seq)
+    }
+
+    /// Same as [`RingBufProducer::enqueue`] but returns the sequence number of the item.
+    pub fn enqueue_with_seq(&self, item: T) -> Option<u64> {
+        self.try_enqueue_with_seq(item).ok()
+    }
+
+    /// Publishes `write_idx` only once every `n` items enqueued by `try_enqueue` and the calls
+    /// built on it, instead of on each item. This saves a Release store and a transfer of the
+    /// cache line to the consumer per item, at the cost of the items staying invisible to the
+    /// consumer until then. They are published earlier by [`Producer::flush`], when the ring
+    /// is full, by any other operation of the producer, and when it is closed or dropped.
+    /// `1`, the default, publishes every item.
+    ///
+    /// # Panics
+    /// Panics when `n` is 0.
+    pub fn set_publish_interval(&mut self, n: usize) {
+        assert!(0 < n, "publish interval must be positive");
+        self.publish_pending();
+        self.publish_interval = n;
+    }
+
+    /// Makes every item enqueued so far visible to the consumer.
+    pub fn flush(&self) {
+        self.publish_pending();
+    }
+
+    fn enqueue_deferred(&self, item: T) -> Result<(), Full<T>> {
+        let buffer = &*self.buffer;
+        let pending = self.pending.get();
+        let pos = buffer
+            .producer
+            .write_idx
+            .load(Ordering::Relaxed)
+            .wrapping_add(pending);
+        if buffer.writable(pos, 1) == 0 {
+            // 書いた分を見せないとconsumerが空けられない
+            self.publish_pending();
+            return Err(Full(item));
+        }
+
+        unsafe {
+            buffer.store(pos, item);
+        }
+        self.pending.set(pending + 1);
+        if self.publish_interval <= pending + 1 {
+            self.publish_pending();
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn publish_pending(&self) {
+        let pending = self.pending.get();
+        if pending != 0 {
+            let write_idx = self.buffer.producer.write_idx.load(Ordering::Relaxed);
+            self.buffer.publish_write(write_idx.wrapping_add(pending));
+            self.pending.set(0);
+        }
+    }
+
+    /// Reserves up to `n` free slots to be written in place.
+    /// The slots are returned as two segments because the run may wrap at the end of the buffer,
+    /// the second segment is empty when it does not.
+    /// Nothing is visible to the consumer until [`Producer::commit`] is called.
+    /// Both segments are empty once the ring is closed.
+    pub fn reserve(&mut self, n: usize) -> (&mut [MaybeUninit<T>], &mut [MaybeUninit<T>]) {
+        let n = if self.is_closed() { 0 } else { n };
+        self.publish_pending();
+        let buffer = &*self.buffer;
+        let write_idx = buffer.producer.write_idx.load(Ordering::Relaxed);
+        let n = n.min(buffer.writable(write_idx, n));
+        let (head, first, second) = buffer.segments(write_idx, n);
         unsafe {
-            let layout = Layout::from_size_align(
-                self.allocated_size * mem::size_of::<usize>(),
-                mem::align_of::<usize>(),
+            (
+                slice::from_raw_parts_mut(head as *mut MaybeUninit<T>, first),
+                slice::from_raw_parts_mut(buffer.buffer as *mut MaybeUninit<T>, second),
             )
-            .unwrap();
-            std::alloc::dealloc(self.buffer as *mut u8, layout);
         }
     }
+
+    /// Publishes the first `k` slots of the last reservation to the consumer.
+    /// Items enqueued since the reservation were written to its head and are published as well.
+    ///
+    /// # Safety
+    /// The first `k` slots returned by [`Producer::reserve`], counted across both segments,
+    /// must have been initialized.
+    pub unsafe fn commit(&mut self, k: usize) {
+        // 未publishの要素は予約の先頭から書かれているので、kに含めてからリセットする
+        let k = k.max(self.pending.replace(0));
+        let buffer = &*self.buffer;
+        let write_idx = buffer.producer.write_idx.load(Ordering::Relaxed);
+        assert!(
+            k <= buffer.writable(write_idx, k),
+            "commit exceeds free slots"
+        );
+        buffer.publish_write(write_idx.wrapping_add(k));
+    }
 }
 
-impl RingBufTrait<usize> for Buffer {
-    fn enqueue(&mut self, item: usize) -> bool {
-        let write_idx = self.write_idx.load(Ordering::Relaxed);
-        if self.cached_read_idx.get() + self.capacity <= write_idx {
-            self.cached_read_idx
-                .set(self.read_idx.load(Ordering::Acquire));
-            assert!(self.cached_read_idx.get() <= write_idx);
-            if write_idx - self.cached_read_idx.get() == self.capacity {
-                return false;
-            }
+impl<T, W: WaitStrategy, S: Storage<T>> Consumer<T, W, S> {
+    /// Sequence number of the next item to be dequeued, see [`Producer::next_seq`].
+    pub fn next_seq(&self) -> u64 {
+        let buffer = &*self.buffer;
+        let published = buffer
+            .consumer
+            .read_laps
+            .seq(buffer.consumer.read_idx.load(Ordering::Relaxed));
+        published + self.pending.get() as u64
+    }
+
+    /// Same as [`RingBufConsumer::try_dequeue`] but returns the item with its sequence number.
+    pub fn try_dequeue_with_seq(&self) -> Result<(u64, T), TryDequeueError> {
+        let seq = self.next_seq();
+        self.try_dequeue().map(|v| (seq, v))
+    }
+
+    /// Same as [`RingBufConsumer::dequeue`] but returns the item with its sequence number.
+    pub fn dequeue_with_seq(&self) -> Option<(u64, T)> {
+        self.try_dequeue_with_seq().ok()
+    }
+
+    /// Returns a reference to the next item without dequeuing it.
+    pub fn peek(&mut self) -> Option<&T> {
+        self.peek_nth(0)
+    }
+
+    /// Returns a reference to the `i`-th readable item (0 is the next one) without dequeuing it.
+    pub fn peek_nth(&mut self, i: usize) -> Option<&T> {
+        self.release_pending();
+        let read_idx = self.buffer.consumer.read_idx.load(Ordering::Relaxed);
+        if i < self.buffer.readable(read_idx, i.saturating_add(1)) {
+            Some(unsafe { &*self.buffer.slot(read_idx.wrapping_add(i)) })
+        } else {
+            None
         }
+    }
 
+    /// Returns up to `n` readable items without moving them out.
+    /// The items are returned as two segments because the run may wrap at the end of the buffer,
+    /// the second segment is empty when it does not.
+    pub fn read_chunk(&mut self, n: usize) -> (&[T], &[T]) {
+        self.release_pending();
+        let buffer = &*self.buffer;
+        let read_idx = buffer.consumer.read_idx.load(Ordering::Relaxed);
+        let n = n.min(buffer.readable(read_idx, n));
+        let (head, first, second) = buffer.segments(read_idx, n);
         unsafe {
-            self.store(write_idx, item);
+            (
+                slice::from_raw_parts(head, first),
+                slice::from_raw_parts(buffer.buffer, second),
+            )
         }
-        self.write_idx
-            .store(write_idx.wrapping_add(1), Ordering::Release);
-        true
     }
 
-    fn dequeue(&mut self) -> Option<usize> {
-        let read_idx = self.read_idx.load(Ordering::Relaxed);
-        if self.cached_write_idx.get() == read_idx {
-            self.cached_write_idx
-                .set(self.write_idx.load(Ordering::Acquire));
-            assert!(read_idx <= self.cached_write_idx.get());
-            if self.cached_write_idx.get() == read_idx {
-                return None;
+    /// Drops the first `k` readable items and hands their slots back to the producer.
+    ///
+    /// # Panics
+    /// Panics when fewer than `k` items are readable.
+    pub fn release(&mut self, k: usize) {
+        self.release_pending();
+        let read_idx = self.buffer.consumer.read_idx.load(Ordering::Relaxed);
+        assert!(
+            k <= self.buffer.readable(read_idx, k),
+            "release exceeds readable items"
+        );
+        if mem::needs_drop::<T>() {
+            for i in 0..k {
+                let v = unsafe { self.buffer.load(read_idx.wrapping_add(i)) };
+                // dropがpanicしても同じ要素を二度dropしないよう先に数える
+                self.pending.set(i + 1);
+                drop(v);
             }
+            self.release_pending();
+        } else {
+            self.buffer.publish_read(read_idx.wrapping_add(k));
         }
+    }
 
-        let v = unsafe { self.load(read_idx) };
-        self.read_idx
-            .store(read_idx.wrapping_add(1), Ordering::Release);
+    /// Returns an iterator that moves out up to `max` items which are readable now.
+    /// The read index is published once when the iterator is dropped.
+    pub fn drain(&mut self, max: usize) -> Drain<'_, T, W, S> {
+        self.release_pending();
+        let start = self.buffer.consumer.read_idx.load(Ordering::Relaxed);
+        let remaining = max.min(self.buffer.readable(start, max));
+        Drain {
+            consumer: self,
+            start,
+            remaining,
+        }
+    }
+
+    /// Consumer side of [`Producer::set_publish_interval`]: publishes `read_idx` only once every
+    /// `n` items dequeued by `try_dequeue` and the calls built on it. The slots are handed
+    /// back to the producer earlier by [`Consumer::flush`], when the ring is empty, by any
+    /// other operation of the consumer, and when it is dropped.
+    ///
+    /// # Panics
+    /// Panics when `n` is 0.
+    pub fn set_publish_interval(&mut self, n: usize) {
+        assert!(0 < n, "publish interval must be positive");
+        self.release_pending();
+        self.publish_interval = n;
+    }
+
+    /// Hands the slots of every item dequeued so far back to the producer.
+    pub fn flush(&self) {
+        self.release_pending();
+    }
+
+    fn dequeue_deferred(&self) -> Result<T, Empty> {
+        if self.publish_interval == 1 {
+            self.release_pending();
+            return (*self.buffer).try_dequeue();
+        }
+        let buffer = &*self.buffer;
+        let pending = self.pending.get();
+        let pos = buffer
+            .consumer
+            .read_idx
+            .load(Ordering::Relaxed)
+            .wrapping_add(pending);
+        if buffer.readable(pos, 1) == 0 {
+            self.release_pending();
+            return Err(Empty);
+        }
+
+        let v = unsafe { buffer.load(pos) };
+        self.pending.set(pending + 1);
+        if self.publish_interval <= pending + 1 {
+            self.release_pending();
+        }
+        Ok(v)
+    }
+
+    #[inline]
+    fn release_pending(&self) {
+        let pending = self.pending.get();
+        if pending != 0 {
+            let read_idx = self.buffer.consumer.read_idx.load(Ordering::Relaxed);
+            self.buffer.publish_read(read_idx.wrapping_add(pending));
+            self.pending.set(0);
+        }
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Drop for Consumer<T, W, S> {
+    fn drop(&mut self) {
+        self.release_pending();
+        self.buffer.consumer_closed.store(true, Ordering::Release);
+        self.buffer.wait.notify();
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Drop for Producer<T, W, S> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+pub struct Drain<'a, T, W: WaitStrategy = BusySpin, S: Storage<T> = Allocated<T>> {
+    consumer: &'a mut Consumer<T, W, S>,
+    start: usize,
+    remaining: usize,
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Iterator for Drain<'_, T, W, S> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        // 読み出し済みの個数はConsumer側に記録するので、Drainがleakしても二重に読まれない
+        let pending = self.consumer.pending.get();
+        let v = unsafe { self.consumer.buffer.load(self.start.wrapping_add(pending)) };
+        self.consumer.pending.set(pending + 1);
+        self.remaining -= 1;
         Some(v)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T, W: WaitStrategy, S: Storage<T>> ExactSizeIterator for Drain<'_, T, W, S> {}
+
+impl<T, W: WaitStrategy, S: Storage<T>> Drop for Drain<'_, T, W, S> {
+    fn drop(&mut self) {
+        self.consumer.release_pending();
+    }
+}
+
+impl<T, S: Storage<T>> Producer<T, AsyncWake, S> {
+    /// Enqueues `item`, suspending the task while the ring is full.
+    /// Dropping the future before it completes drops `item` without enqueueing it.
+    pub fn send(&mut self, item: T) -> SendFuture<'_, T, S> {
+        SendFuture {
+            producer: self,
+            item: Some(item),
+            registered: false,
+        }
+    }
+}
+
+impl<T, S: Storage<T>> Consumer<T, AsyncWake, S> {
+    /// Dequeues an item, suspending the task while the ring is empty.
+    /// Dropping the future before it completes never loses an item.
+    pub fn recv(&mut self) -> RecvFuture<'_, T, S> {
+        RecvFuture {
+            consumer: self,
+            registered: false,
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct SendFuture<'a, T, S: Storage<T> = Allocated<T>> {
+    producer: &'a mut Producer<T, AsyncWake, S>,
+    item: Option<T>,
+    registered: bool,
+}
+
+// itemをpinされた参照として外に出さないので、TがUnpinでなくても移動してよい
+impl<T, S: Storage<T>> Unpin for SendFuture<'_, T, S> {}
+
+impl<T, S: Storage<T>> Future for SendFuture<'_, T, S> {
+    type Output = Result<(), Disconnected<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let item = this
+            .item
+            .take()
+            .expect("SendFuture polled after completion");
+        let item = match this.producer.try_enqueue(item) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TryEnqueueError::Disconnected(v)) => return Poll::Ready(Err(Disconnected(v))),
+            Err(TryEnqueueError::Full(v)) => v,
+        };
+        // 満杯のときだけ登録し、登録後に再確認して登録前のpublishを取りこぼさない
+        this.producer
+            .buffer
+            .wait
+            .register(AsyncWake::PRODUCER, cx.waker());
+        this.registered = true;
+        match this.producer.try_enqueue(item) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TryEnqueueError::Disconnected(v)) => Poll::Ready(Err(Disconnected(v))),
+            Err(TryEnqueueError::Full(v)) => {
+                this.item = Some(v);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T, S: Storage<T>> Drop for SendFuture<'_, T, S> {
+    fn drop(&mut self) {
+        if self.registered {
+            self.producer.buffer.wait.unregister(AsyncWake::PRODUCER);
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct RecvFuture<'a, T, S: Storage<T> = Allocated<T>> {
+    consumer: &'a mut Consumer<T, AsyncWake, S>,
+    registered: bool,
+}
+
+impl<T, S: Storage<T>> Future for RecvFuture<'_, T, S> {
+    type Output = Result<T, Disconnected>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.consumer.try_dequeue() {
+            Ok(v) => return Poll::Ready(Ok(v)),
+            Err(TryDequeueError::Disconnected) => return Poll::Ready(Err(Disconnected(()))),
+            Err(TryDequeueError::Empty) => {}
+        }
+        this.consumer
+            .buffer
+            .wait
+            .register(AsyncWake::CONSUMER, cx.waker());
+        this.registered = true;
+        match this.consumer.try_dequeue() {
+            Ok(v) => Poll::Ready(Ok(v)),
+            Err(TryDequeueError::Disconnected) => Poll::Ready(Err(Disconnected(()))),
+            Err(TryDequeueError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T, S: Storage<T>> Drop for RecvFuture<'_, T, S> {
+    fn drop(&mut self) {
+        if self.registered {
+            self.consumer.buffer.wait.unregister(AsyncWake::CONSUMER);
+        }
+    }
+}
+
+impl<W: WaitStrategy, S: Storage<u8>> io::Write for Producer<u8, W, S> {
+    /// Copies as many bytes as fit. Fails with `WouldBlock` when the ring is full
+    /// and with `BrokenPipe` when the ring is closed.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.is_closed() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        // 遅延中の要素を先に公開してから後ろに書く
+        match RingBufBatchProducer::enqueue_slice(self, buf) {
+            0 => Err(io::ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+
+    /// Publishes the bytes enqueued with a publish interval, see [`Producer::flush`].
+    fn flush(&mut self) -> io::Result<()> {
+        Producer::flush(self);
+        Ok(())
+    }
+}
+
+impl<W: WaitStrategy, S: Storage<u8>> Consumer<u8, W, S> {
+    /// Same as `read_chunk` but fails with `WouldBlock` when the ring is empty,
+    /// and returns empty segments at the end of the stream.
+    fn read_chunk_io(&mut self, n: usize) -> io::Result<(&[u8], &[u8])> {
+        // closeを先に見ておけば、その後に空なら本当に終わり
+        let closed = self.is_closed();
+        let (first, second) = self.read_chunk(n);
+        if first.is_empty() && !closed {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok((first, second))
+    }
+}
+
+impl<W: WaitStrategy, S: Storage<u8>> io::Read for Consumer<u8, W, S> {
+    /// Copies up to `buf.len()` bytes. Fails with `WouldBlock` when the ring is empty,
+    /// returns `Ok(0)` once the producer has closed the ring and it is drained.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (first, second) = self.read_chunk_io(buf.len())?;
+        let n = first.len() + second.len();
+        buf[..first.len()].copy_from_slice(first);
+        buf[first.len()..n].copy_from_slice(second);
+        self.release(n);
+        Ok(n)
+    }
+}
+
+impl<W: WaitStrategy, S: Storage<u8>> io::BufRead for Consumer<u8, W, S> {
+    /// Returns the readable bytes up to the end of the buffer, or all of them when the buffer
+    /// is mirrored. Same errors as `read`.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.read_chunk_io(usize::MAX).map(|(first, _)| first)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.release(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        mem::{self, MaybeUninit},
+        pin::pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll, Wake, Waker},
+        thread::{self, Thread},
+    };
+
+    use super::Buffer;
+    use crate::{
+        helper::CACHELINE_LEN,
+        wait::{AsyncWake, BusySpin},
+        Disconnected, RingBufBatchConsumer, RingBufBatchProducer, RingBufBlockingConsumer,
+        RingBufBlockingProducer, RingBufConsumer, RingBufProducer, TryDequeueError,
+        TryEnqueueError,
+    };
+
+    // テスト用の最小のexecutor。wakeでスレッドをunparkする
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut f = pin!(f);
+        loop {
+            match f.as_mut().poll(&mut cx) {
+                Poll::Ready(v) => return v,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct CountWaker(AtomicUsize);
+
+    impl Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_reserve_commit() {
+        let (mut p, mut c) = super::make::<u32>(8);
+        let mut next = 0;
+        let mut expect = 0;
+        for round in 0..20 {
+            let want = round % 7 + 1;
+            let (a, b) = p.reserve(want);
+            let len = a.len() + b.len();
+            assert!(len <= want);
+            for slot in a.iter_mut().chain(b.iter_mut()) {
+                *slot = MaybeUninit::new(next);
+                next += 1;
+            }
+            unsafe { p.commit(len) };
+
+            let (a, b) = c.read_chunk(usize::MAX);
+            let read = a.len() + b.len();
+            for v in a.iter().chain(b.iter()) {
+                assert_eq!(*v, expect);
+                expect += 1;
+            }
+            // 一部だけ返して残りは次の周回で読む
+            let keep = round % 3;
+            let release = read.saturating_sub(keep);
+            expect -= (read - release) as u32;
+            c.release(release);
+        }
+        while let Some(v) = c.dequeue() {
+            assert_eq!(v, expect);
+            expect += 1;
+        }
+        assert_eq!(expect, next);
+    }
+
+    #[test]
+    fn test_reserve_commit_deferred() {
+        let (mut p, c) = super::make::<u32>(8);
+        p.set_publish_interval(4);
+        assert!(p.enqueue(0));
+        let (a, _) = p.reserve(3);
+        assert_eq!(a.len(), 3);
+        a[2] = MaybeUninit::new(3);
+        // 予約の先頭2つはenqueueで埋める
+        assert!(p.enqueue(1));
+        assert!(p.enqueue(2));
+        unsafe { p.commit(3) };
+        assert!(p.enqueue(4));
+        drop(p);
+        assert_eq!(c.try_iter().collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+    }
+
+    #[test]
+    fn test_reserve_wraps() {
+        let (mut p, mut c) = super::make::<u32>(8);
+        for i in 0..5 {
+            assert!(p.enqueue(i));
+        }
+        c.release(5);
+        let (a, b) = p.reserve(8);
+        assert_eq!((a.len(), b.len()), (3, 5));
+        for (i, slot) in a.iter_mut().chain(b.iter_mut()).enumerate() {
+            *slot = MaybeUninit::new(i as u32);
+        }
+        unsafe { p.commit(8) };
+        assert_eq!(p.reserve(1).0.len(), 0);
+
+        let (a, b) = c.read_chunk(8);
+        assert_eq!(a, [0, 1, 2]);
+        assert_eq!(b, [3, 4, 5, 6, 7]);
+        c.release(8);
+        assert_eq!(c.dequeue(), None);
+    }
+
+    #[test]
+    fn test_mirrored_single_segment() {
+        let (mut p, mut c) = super::make_mirrored::<u32>(8);
+        let buf = p.buffer.clone();
+        if buf.backing() != crate::Backing::Mirrored {
+            // mapできない環境ではheapにfallbackしている
+            return;
+        }
+        let len = buf.position_mask + 1;
+        // 末尾の手前まで進める
+        for _ in 0..(len - 3) {
+            assert!(p.enqueue(0));
+            assert_eq!(c.dequeue(), Some(0));
+        }
+        let (a, b) = p.reserve(8);
+        assert_eq!((a.len(), b.len()), (8, 0));
+        for (i, slot) in a.iter_mut().enumerate() {
+            *slot = MaybeUninit::new(i as u32);
+        }
+        unsafe { p.commit(8) };
+        let (a, b) = c.read_chunk(8);
+        assert_eq!(a, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(b.is_empty());
+        c.release(8);
+
+        assert_eq!(p.enqueue_slice(&[10, 11, 12, 13, 14]), 5);
+        let mut dst = [MaybeUninit::uninit(); 5];
+        assert_eq!(c.dequeue_into(&mut dst), 5);
+        assert_eq!(
+            dst.map(|v| unsafe { v.assume_init() }),
+            [10, 11, 12, 13, 14]
+        );
+    }
+
+    #[test]
+    fn test_release_drops_items() {
+        let (p, mut c) = super::make::<String>(4);
+        for i in 0..4 {
+            assert!(p.enqueue(i.to_string()));
+        }
+        let (a, _) = c.read_chunk(2);
+        assert_eq!(a, ["0", "1"]);
+        c.release(2);
+        assert_eq!(c.dequeue(), Some("2".to_string()));
+    }
+
+    #[test]
+    fn test_layout() {
+        type B = Buffer<u64, BusySpin>;
+        assert!(mem::align_of::<B>() >= CACHELINE_LEN);
+        let producer = mem::offset_of!(B, producer);
+        let consumer = mem::offset_of!(B, consumer);
+        let wait = mem::offset_of!(B, wait);
+        assert_eq!(producer % CACHELINE_LEN, 0);
+        assert_eq!(consumer % CACHELINE_LEN, 0);
+        // 閉じたフラグなどの読み取り専用の領域、producer、consumer、それ以降が別の行に乗る
+        assert!(mem::offset_of!(B, backing) < producer);
+        assert!(producer + CACHELINE_LEN <= consumer);
+        assert!(consumer + CACHELINE_LEN <= wait);
+
+        // Arcの中でも行の先頭に置かれる
+        let (p, _) = super::make::<u64>(4);
+        let arc = &p.buffer;
+        let base = Arc::as_ptr(arc) as usize;
+        assert_eq!(base % CACHELINE_LEN, 0);
+        let write_idx = &arc.producer.write_idx as *const _ as usize;
+        let read_idx = &arc.consumer.read_idx as *const _ as usize;
+        assert_ne!(write_idx / CACHELINE_LEN, read_idx / CACHELINE_LEN);
+    }
+
+    #[test]
+    fn test_publish_interval() {
+        let (mut p, mut c) = super::make::<u32>(8);
+        p.set_publish_interval(4);
+        c.set_publish_interval(4);
+        for i in 0..3 {
+            assert!(p.enqueue(i));
+        }
+        // 4個目まではconsumerから見えない
+        assert_eq!(c.dequeue(), None);
+        assert_eq!(p.next_seq(), 3);
+        assert!(p.enqueue(3));
+        assert_eq!(c.dequeue_with_seq(), Some((0, 0)));
+        assert_eq!(c.dequeue(), Some(1));
+        assert_eq!(c.next_seq(), 2);
+        assert_eq!(p.free_slots(), 4);
+        c.flush();
+        assert_eq!(p.free_slots(), 6);
+
+        // 満杯になったら書いた分を見せる
+        for i in 4..10 {
+            assert!(p.enqueue(i));
+        }
+        assert_eq!(p.try_enqueue(10), Err(crate::TryEnqueueError::Full(10)));
+        assert_eq!(c.len(), 8);
+        // consumerが解放するまでは空かない
+        assert_eq!(c.dequeue(), Some(2));
+        assert!(!p.enqueue(10));
+        c.flush();
+        assert!(p.enqueue(10));
+        drop(p);
+        assert_eq!(
+            c.drain(usize::MAX).collect::<Vec<_>>(),
+            (3..=10).collect::<Vec<_>>()
+        );
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+    }
+
+    #[test]
+    fn test_publish_interval_multi_thread() {
+        let count = 100_000;
+        let (mut p, mut c) = super::make::<usize>(64);
+        p.set_publish_interval(16);
+        c.set_publish_interval(8);
+        let h = thread::spawn(move || {
+            for i in 0..count {
+                while !p.enqueue(i) {
+                    thread::yield_now();
+                }
+            }
+        });
+        for i in 0..count {
+            loop {
+                if let Some(v) = c.dequeue() {
+                    assert_eq!(v, i);
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+        h.join().unwrap();
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+    }
+
+    #[test]
+    fn test_seq() {
+        let (mut p, mut c) = super::make::<u32>(4);
+        assert_eq!((p.next_seq(), c.next_seq()), (0, 0));
+        assert_eq!(p.enqueue_with_seq(10), Some(0));
+        assert_eq!(p.enqueue_with_seq(11), Some(1));
+        assert_eq!(c.dequeue_with_seq(), Some((0, 10)));
+
+        // バッチやreserve/commit、drainで進めた分も数える
+        assert_eq!(p.enqueue_slice(&[12, 13, 14]), 3);
+        assert_eq!(p.try_enqueue_with_seq(15), Err(TryEnqueueError::Full(15)));
+        assert_eq!(c.drain(2).collect::<Vec<_>>(), [11, 12]);
+        assert_eq!(c.next_seq(), 3);
+        let (a, _) = p.reserve(1);
+        a[0].write(15);
+        unsafe { p.commit(1) };
+        assert_eq!(p.next_seq(), 6);
+        c.release(1);
+        assert_eq!(c.dequeue_with_seq(), Some((4, 14)));
+        assert_eq!(c.dequeue_with_seq(), Some((5, 15)));
+        assert_eq!(c.try_dequeue_with_seq(), Err(TryDequeueError::Empty));
+
+        drop(p);
+        assert_eq!(c.try_dequeue_with_seq(), Err(TryDequeueError::Disconnected));
+        assert_eq!(c.next_seq(), 6);
+    }
+
+    #[test]
+    fn test_peek() {
+        let (p, mut c) = super::make::<u32>(5);
+        for i in 0..5 {
+            assert!(p.enqueue(i));
+        }
+        assert_eq!(c.peek(), Some(&0));
+        assert_eq!(c.peek_nth(4), Some(&4));
+        assert_eq!(c.peek_nth(5), None);
+        assert_eq!(c.peek_nth(usize::MAX), None);
+        assert_eq!(c.dequeue(), Some(0));
+        assert_eq!(c.peek(), Some(&1));
+        while c.dequeue().is_some() {}
+        assert_eq!(c.peek(), None);
+    }
+
+    #[test]
+    fn test_async_send_recv() {
+        let count = 1000;
+        let (mut p, mut c) = super::make_with_wait::<u32, _>(4, AsyncWake::default());
+        let h = thread::spawn(move || {
+            block_on(async {
+                for i in 0..count {
+                    p.send(i).await.unwrap();
+                }
+            })
+        });
+        block_on(async {
+            for i in 0..count {
+                assert_eq!(c.recv().await, Ok(i));
+            }
+            h.join().unwrap();
+            assert_eq!(c.recv().await, Err(Disconnected(())));
+        });
+    }
+
+    #[test]
+    fn test_async_cancel() {
+        let (mut p, mut c) = super::make_with_wait::<String, _>(2, AsyncWake::default());
+        let wake = Arc::new(CountWaker::default());
+        let waker = Waker::from(wake.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // 空のときのrecvは登録だけして何も取らない
+        {
+            let mut f = pin!(c.recv());
+            assert!(f.as_mut().poll(&mut cx).is_pending());
+        }
+        assert!(p.enqueue("0".to_string()));
+        assert!(p.enqueue("1".to_string()));
+        // recvを落とした後なのでpublishで起こされない
+        assert_eq!(wake.0.load(Ordering::SeqCst), 0);
+
+        // 満杯のときのsendを落とすと要素はringに入らない
+        {
+            let mut f = pin!(p.send("2".to_string()));
+            assert!(f.as_mut().poll(&mut cx).is_pending());
+            assert!(f.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(c.dequeue(), Some("0".to_string()));
+        assert_eq!(wake.0.load(Ordering::SeqCst), 0);
+
+        // 待っているsendは相手のpublishで起こされる
+        assert!(p.enqueue("2".to_string()));
+        {
+            let mut f = pin!(p.send("3".to_string()));
+            assert!(f.as_mut().poll(&mut cx).is_pending());
+            assert_eq!(c.dequeue(), Some("1".to_string()));
+            assert_eq!(wake.0.load(Ordering::SeqCst), 1);
+            assert_eq!(f.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        }
+        assert_eq!(c.dequeue(), Some("2".to_string()));
+        assert_eq!(c.dequeue(), Some("3".to_string()));
+        assert_eq!(c.dequeue(), None);
+    }
+
+    #[test]
+    fn test_io() {
+        use std::io::{BufRead, ErrorKind, Read, Write};
+
+        let (mut p, mut c) = super::make::<u8>(8);
+        let mut buf = [0; 16];
+        assert_eq!(c.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(c.fill_buf().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        // 末尾を跨ぐ書き込みと読み出し
+        p.write_all(b"abcde").unwrap();
+        assert_eq!(c.read(&mut buf[..5]).unwrap(), 5);
+        assert_eq!(p.write(b"0123456789").unwrap(), 8);
+        assert_eq!(p.write(b"x").unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(c.read(&mut buf).unwrap(), 8);
+        assert_eq!(&buf[..8], b"01234567");
+
+        // fill_bufは末尾までの連続した部分を返す
+        p.write_all(b"hello").unwrap();
+        assert_eq!(c.fill_buf().unwrap(), b"hel");
+        c.consume(2);
+        assert_eq!(c.fill_buf().unwrap(), b"l");
+        c.consume(1);
+        assert_eq!(c.fill_buf().unwrap(), b"lo");
+
+        p.write_all(b"\nworld").unwrap();
+        drop(p);
+        let mut line = String::new();
+        assert_eq!(c.read_line(&mut line).unwrap(), 3);
+        assert_eq!(line, "lo\n");
+        let mut rest = String::new();
+        assert_eq!(c.read_to_string(&mut rest).unwrap(), 5);
+        assert_eq!(rest, "world");
+        assert_eq!(c.read(&mut buf).unwrap(), 0);
+
+        let (mut p, c) = super::make::<u8>(8);
+        drop(c);
+        assert_eq!(p.write(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_io_publish_interval() {
+        use std::io::{Read, Write};
+
+        let (mut p, mut c) = super::make::<u8>(8);
+        p.set_publish_interval(4);
+        assert!(p.enqueue(b'a'));
+        assert!(p.enqueue(b'b'));
+        // 未公開の2byteを上書きせずに後ろへ書く
+        p.write_all(b"cd").unwrap();
+        assert!(p.enqueue(b'e'));
+        let mut buf = [0; 8];
+        assert_eq!(c.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"abcd");
+        Write::flush(&mut p).unwrap();
+        assert_eq!(c.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'e');
+    }
 }
@@ -0,0 +1,155 @@
+//! Bounded MPSC ring. Each slot has a sequence number in the style of Dmitry Vyukov's
+//! bounded queue, producers claim a slot by CAS on `write_idx` and the single consumer
+//! only reads the sequence of the next slot.
+
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use crate::{
+    error::{Empty, TryDequeueError},
+    helper::{dequeue_or_disconnected, RingBufBlockingConsumer, RingBufConsumer},
+    vyukov,
+    wait::{BusySpin, WaitStrategy},
+};
+
+pub use crate::vyukov::{Buffer, Producer};
+
+pub struct Consumer<T, W: WaitStrategy = BusySpin> {
+    buffer: Arc<Buffer<T, W>>,
+    // &selfから読むので、Syncにすると複数スレッドから同時に読めてしまう
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+unsafe impl<T: Send, W: WaitStrategy> Send for Consumer<T, W> {}
+
+pub fn make<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    make_with_wait(capacity, BusySpin)
+}
+
+/// Same as [`make`] but the blocking operations of the handles wait with `wait`.
+/// The buffer is reachable only through the handles, so there is a single consumer.
+pub fn make_with_wait<T, W: WaitStrategy>(
+    capacity: usize,
+    wait: W,
+) -> (Producer<T, W>, Consumer<T, W>) {
+    let (p, buffer) = vyukov::make_with_wait(capacity, wait);
+    (
+        p,
+        Consumer {
+            buffer,
+            _not_sync: PhantomData,
+        },
+    )
+}
+
+impl<T, W: WaitStrategy> RingBufConsumer<T> for Consumer<T, W> {
+    fn try_dequeue(&self) -> Result<T, TryDequeueError> {
+        dequeue_or_disconnected(|| self.try_dequeue_once(), || self.is_closed())
+    }
+}
+
+impl<T, W: WaitStrategy> RingBufBlockingConsumer<T> for Consumer<T, W> {
+    /// `true` when all producers are dropped or one of them has closed the ring.
+    /// Items enqueued before the close can still be dequeued.
+    fn is_closed(&self) -> bool {
+        self.buffer.producer_closed.load(Ordering::Acquire)
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    /// Number of items in the ring, including ones producers are still writing.
+    /// `dequeue` may fail while the next item is being written even if this is not zero.
+    fn len(&self) -> usize {
+        self.buffer.claimed()
+    }
+
+    fn is_readable(&self) -> bool {
+        self.is_closed() || self.buffer.is_readable()
+    }
+
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool {
+        self.buffer.wait.wait_until(ready, deadline)
+    }
+}
+
+impl<T, W: WaitStrategy> Consumer<T, W> {
+    #[inline]
+    fn try_dequeue_once(&self) -> Result<T, Empty> {
+        // ConsumerはCloneもSyncも実装しないので、同時に読むスレッドは無い
+        unsafe { self.buffer.try_dequeue_single() }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for Consumer<T, W> {
+    fn drop(&mut self) {
+        self.buffer.consumer_closed.store(true, Ordering::Release);
+        self.buffer.wait.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use crate::{RingBufBlockingConsumer, RingBufConsumer, RingBufProducer, TryDequeueError};
+
+    #[test]
+    fn test_multi_producer() {
+        let producers = 4;
+        let count = 1000;
+        let (p, c) = super::make::<u32>(16);
+        let handles = (0..producers)
+            .map(|id| {
+                let p = p.clone();
+                thread::spawn(move || {
+                    for i in 0..count {
+                        while !p.enqueue(id * count + i) {
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(p);
+
+        // Producerごとの順序は保たれる
+        let mut last = vec![None; producers as usize];
+        let mut seen = HashSet::new();
+        loop {
+            match c.try_dequeue() {
+                Ok(v) => {
+                    let id = (v / count) as usize;
+                    assert!(last[id] < Some(v));
+                    last[id] = Some(v);
+                    assert!(seen.insert(v));
+                }
+                Err(TryDequeueError::Empty) => thread::yield_now(),
+                Err(TryDequeueError::Disconnected) => break,
+            }
+        }
+        assert_eq!(seen.len(), (producers * count) as usize);
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_close_on_last_producer() {
+        let (p, c) = super::make::<u32>(4);
+        let p2 = p.clone();
+        assert!(p.enqueue(1));
+        drop(p);
+        assert!(!c.is_closed());
+        assert!(p2.enqueue(2));
+        drop(p2);
+        assert_eq!(c.try_dequeue(), Ok(1));
+        assert_eq!(c.try_dequeue(), Ok(2));
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+    }
+}
@@ -0,0 +1,157 @@
+//! Bounded MPMC ring. Same slot sequence numbers as [`crate::r4`], consumers also claim
+//! a slot by CAS on `read_idx` so both handles can be cloned.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use crate::{
+    error::TryDequeueError,
+    helper::{dequeue_or_disconnected, RingBufBlockingConsumer, RingBufConsumer},
+    vyukov,
+    wait::{BusySpin, WaitStrategy},
+};
+
+pub use crate::vyukov::{Buffer, Producer};
+
+/// Cloneable handle, every clone may dequeue from its own thread.
+pub struct Consumer<T, W: WaitStrategy = BusySpin> {
+    buffer: Arc<Buffer<T, W>>,
+}
+
+unsafe impl<T: Send, W: WaitStrategy> Send for Consumer<T, W> {}
+unsafe impl<T: Send, W: WaitStrategy> Sync for Consumer<T, W> {}
+
+pub fn make<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    make_with_wait(capacity, BusySpin)
+}
+
+/// Same as [`make`] but the blocking operations of the handles wait with `wait`.
+pub fn make_with_wait<T, W: WaitStrategy>(
+    capacity: usize,
+    wait: W,
+) -> (Producer<T, W>, Consumer<T, W>) {
+    let (p, buffer) = vyukov::make_with_wait(capacity, wait);
+    (p, Consumer { buffer })
+}
+
+impl<T, W: WaitStrategy> RingBufConsumer<T> for Consumer<T, W> {
+    fn try_dequeue(&self) -> Result<T, TryDequeueError> {
+        dequeue_or_disconnected(|| self.buffer.try_dequeue(), || self.is_closed())
+    }
+}
+
+impl<T, W: WaitStrategy> RingBufBlockingConsumer<T> for Consumer<T, W> {
+    /// `true` when all producers are dropped or one of them has closed the ring.
+    /// Items enqueued before the close can still be dequeued.
+    fn is_closed(&self) -> bool {
+        self.buffer.producer_closed.load(Ordering::Acquire)
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    /// Number of items in the ring, including ones producers are still writing.
+    /// Other consumers may take them at any time.
+    fn len(&self) -> usize {
+        self.buffer.claimed()
+    }
+
+    fn is_readable(&self) -> bool {
+        self.is_closed() || self.buffer.is_readable()
+    }
+
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool {
+        self.buffer.wait.wait_until(ready, deadline)
+    }
+}
+
+impl<T, W: WaitStrategy> Clone for Consumer<T, W> {
+    fn clone(&self) -> Self {
+        self.buffer.consumers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            buffer: self.buffer.clone(),
+        }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for Consumer<T, W> {
+    fn drop(&mut self) {
+        // 最後のConsumerがdropされたときだけ閉じる
+        if self.buffer.consumers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.buffer.consumer_closed.store(true, Ordering::Release);
+            self.buffer.wait.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use crate::{RingBufBlockingProducer, RingBufConsumer, RingBufProducer, TryDequeueError};
+
+    #[test]
+    fn test_multi_producer_consumer() {
+        let (producers, consumers) = (3, 3);
+        let count = 1000;
+        let (p, c) = super::make::<u32>(16);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let h_ps = (0..producers)
+            .map(|id| {
+                let p = p.clone();
+                thread::spawn(move || {
+                    for i in 0..count {
+                        while !p.enqueue(id * count + i) {
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let h_cs = (0..consumers)
+            .map(|_| {
+                let c = c.clone();
+                let received = received.clone();
+                thread::spawn(move || {
+                    let mut local = vec![];
+                    loop {
+                        match c.try_dequeue() {
+                            Ok(v) => local.push(v),
+                            Err(TryDequeueError::Empty) => thread::yield_now(),
+                            Err(TryDequeueError::Disconnected) => break,
+                        }
+                    }
+                    received.lock().unwrap().extend(local);
+                })
+            })
+            .collect::<Vec<_>>();
+        drop((p, c));
+        for h in h_ps.into_iter().chain(h_cs) {
+            h.join().unwrap();
+        }
+
+        // 全ての要素がちょうど1回ずつ読まれる
+        let mut received = received.lock().unwrap();
+        received.sort_unstable();
+        assert_eq!(*received, (0..producers * count).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_close_on_last_consumer() {
+        let (p, c) = super::make::<u32>(4);
+        let c2 = c.clone();
+        drop(c);
+        assert!(!p.is_closed());
+        assert!(p.enqueue(1));
+        assert_eq!(c2.dequeue(), Some(1));
+        drop(c2);
+        assert!(p.is_closed());
+        assert!(!p.enqueue(2));
+    }
+}
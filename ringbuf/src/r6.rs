@@ -0,0 +1,377 @@
+//! Broadcast ring. One producer, every consumer (subscriber) receives a clone of every item.
+//! Each subscriber has its own `read_idx` and the producer is gated by the slowest one.
+//! Subscribers can attach and detach while the producer is running.
+
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    error::{Empty, Full, TryDequeueError, TryEnqueueError},
+    helper::{dequeue_or_disconnected, CachePadded, RingBufConsumer, RingBufProducer},
+    storage::Allocated,
+};
+
+// 購読者ごとの読み出し位置。他の購読者やProducerとcachelineを共有しないようにalignする
+struct Cursor {
+    read_idx: CachePadded<AtomicUsize>,
+}
+
+impl Cursor {
+    fn new(read_idx: usize) -> Self {
+        Self {
+            read_idx: CachePadded::new(AtomicUsize::new(read_idx)),
+        }
+    }
+}
+
+#[repr(C)]
+pub struct Buffer<T> {
+    buffer: Allocated<T>,
+    capacity: usize,
+    position_mask: usize,
+    producer_closed: AtomicBool,
+    producer: CachePadded<ProducerSide>,
+    // attach/detachと、キャッシュでは満杯に見えるときのgatingの再計算でだけlockする
+    cursors: Mutex<Vec<Arc<Cursor>>>,
+}
+
+#[derive(Default)]
+struct ProducerSide {
+    write_idx: AtomicUsize,
+    // Producerだけが触る。最も遅い購読者のread_idxのキャッシュと、初期化済みのスロット数
+    gating_idx: Cell<usize>,
+    written: Cell<usize>,
+}
+unsafe impl<T: Send + Sync> Sync for Buffer<T> {}
+
+/// A subscriber. Cloning it attaches a new subscriber at the same position.
+pub struct Consumer<T> {
+    buffer: Arc<Buffer<T>>,
+    cursor: Arc<Cursor>,
+    cached_write_idx: Cell<usize>,
+}
+
+/// Not `Sync`, so there is a single thread enqueueing at a time.
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<ringbuf::r6::Producer<u32>>();
+/// ```
+pub struct Producer<T> {
+    buffer: Arc<Buffer<T>>,
+    // gating_idxとwrittenを&selfから更新するので、Syncにすると同時に書けてしまう
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+// 複数の購読者が同じ要素を同時にcloneするのでTはSyncも必要
+unsafe impl<T: Send + Sync> Send for Consumer<T> {}
+unsafe impl<T: Send + Sync> Send for Producer<T> {}
+
+impl<T> Buffer<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buffer: Allocated::heap(capacity),
+            capacity,
+            position_mask: capacity.next_power_of_two() - 1,
+            producer_closed: AtomicBool::new(false),
+            producer: CachePadded::default(),
+            cursors: Mutex::new(vec![]),
+        }
+    }
+
+    #[inline]
+    unsafe fn slot(&self, pos: usize) -> *mut T {
+        self.buffer.as_ptr().add(pos & self.position_mask)
+    }
+
+    /// Registers a subscriber starting at `read_idx`, or at the current `write_idx`.
+    fn attach(&self, read_idx: Option<usize>) -> Arc<Cursor> {
+        let mut cursors = self.cursors.lock().unwrap();
+        let read_idx = read_idx.unwrap_or_else(|| self.producer.write_idx.load(Ordering::Acquire));
+        let cursor = Arc::new(Cursor::new(read_idx));
+        cursors.push(cursor.clone());
+        cursor
+    }
+
+    fn detach(&self, cursor: &Arc<Cursor>) {
+        self.cursors
+            .lock()
+            .unwrap()
+            .retain(|c| !Arc::ptr_eq(c, cursor));
+    }
+
+    /// `read_idx` of the slowest subscriber, `write_idx` when nobody is subscribed.
+    fn min_read_idx(&self, write_idx: usize) -> usize {
+        let cursors = self.cursors.lock().unwrap();
+        let lag = cursors
+            .iter()
+            .map(|c| write_idx.wrapping_sub(c.read_idx.load(Ordering::Acquire)))
+            .max()
+            .unwrap_or(0);
+        write_idx.wrapping_sub(lag)
+    }
+
+    /// Must be called only from the producer, which the `Producer` handle guarantees.
+    /// The item is dropped when the slot is overwritten, after every subscriber has read it.
+    fn try_enqueue(&self, item: T) -> Result<(), Full<T>> {
+        let write_idx = self.producer.write_idx.load(Ordering::Relaxed);
+        if self.capacity <= write_idx.wrapping_sub(self.producer.gating_idx.get()) {
+            self.producer.gating_idx.set(self.min_read_idx(write_idx));
+            if self.capacity <= write_idx.wrapping_sub(self.producer.gating_idx.get()) {
+                return Err(Full(item));
+            }
+        }
+
+        unsafe {
+            let slot = self.slot(write_idx);
+            // 一周した後は前の周回の要素が残っている
+            if self.producer.written.get() == self.position_mask + 1 {
+                ptr::drop_in_place(slot);
+            } else {
+                self.producer.written.set(self.producer.written.get() + 1);
+            }
+            ptr::write(slot, item);
+        }
+        self.producer
+            .write_idx
+            .store(write_idx.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            for i in 0..self.producer.written.get() {
+                ptr::drop_in_place(self.buffer.as_ptr().add(i));
+            }
+        }
+    }
+}
+
+/// The buffer is reachable only through the handles, so there is a single producer.
+pub fn make<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let arc = Arc::new(Buffer::with_capacity(capacity));
+    let cursor = arc.attach(None);
+
+    (
+        Producer {
+            buffer: arc.clone(),
+            _not_sync: PhantomData,
+        },
+        Consumer {
+            buffer: arc,
+            cursor,
+            cached_write_idx: Cell::new(0),
+        },
+    )
+}
+
+impl<T> RingBufProducer<T> for Producer<T> {
+    /// Fails with `Full` while the slowest subscriber has not read `capacity` items.
+    /// Succeeds without any subscriber, such items are never delivered.
+    fn try_enqueue(&self, item: T) -> Result<(), TryEnqueueError<T>> {
+        if self.is_closed() {
+            return Err(TryEnqueueError::Disconnected(item));
+        }
+        (*self.buffer).try_enqueue(item).map_err(Into::into)
+    }
+}
+
+impl<T: Clone> RingBufConsumer<T> for Consumer<T> {
+    fn try_dequeue(&self) -> Result<T, TryDequeueError> {
+        dequeue_or_disconnected(|| self.try_recv(), || self.is_closed())
+    }
+}
+
+impl<T> Producer<T> {
+    /// Attaches a new subscriber which receives the items enqueued from now on.
+    pub fn subscribe(&self) -> Consumer<T> {
+        let cursor = self.buffer.attach(None);
+        let read_idx = cursor.read_idx.load(Ordering::Relaxed);
+        Consumer {
+            buffer: self.buffer.clone(),
+            cursor,
+            cached_write_idx: Cell::new(read_idx),
+        }
+    }
+
+    /// Number of attached subscribers.
+    pub fn subscribers(&self) -> usize {
+        self.buffer.cursors.lock().unwrap().len()
+    }
+
+    /// Marks the ring as closed. Subscribers get `Disconnected` once they read the remaining
+    /// items. Dropping the producer closes the ring as well.
+    pub fn close(&self) {
+        self.buffer.producer_closed.store(true, Ordering::Release);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.buffer.producer_closed.load(Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T> Consumer<T> {
+    fn try_recv(&self) -> Result<T, Empty>
+    where
+        T: Clone,
+    {
+        let read_idx = self.cursor.read_idx.load(Ordering::Relaxed);
+        if self.cached_write_idx.get() == read_idx {
+            self.cached_write_idx
+                .set(self.buffer.producer.write_idx.load(Ordering::Acquire));
+            if self.cached_write_idx.get() == read_idx {
+                return Err(Empty);
+            }
+        }
+
+        // read_idxを進めるまでProducerはこのスロットを上書きしない
+        let v = unsafe { (*self.buffer.slot(read_idx)).clone() };
+        self.cursor
+            .read_idx
+            .store(read_idx.wrapping_add(1), Ordering::Release);
+        Ok(v)
+    }
+
+    /// `true` when the producer has closed the ring.
+    /// Items enqueued before the close can still be dequeued.
+    pub fn is_closed(&self) -> bool {
+        self.buffer.producer_closed.load(Ordering::Acquire)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    /// Number of items this subscriber has not read yet.
+    pub fn len(&self) -> usize {
+        let read_idx = self.cursor.read_idx.load(Ordering::Relaxed);
+        self.buffer
+            .producer
+            .write_idx
+            .load(Ordering::Acquire)
+            .wrapping_sub(read_idx)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for Consumer<T> {
+    fn clone(&self) -> Self {
+        let read_idx = self.cursor.read_idx.load(Ordering::Relaxed);
+        Self {
+            buffer: self.buffer.clone(),
+            cursor: self.buffer.attach(Some(read_idx)),
+            cached_write_idx: Cell::new(self.cached_write_idx.get()),
+        }
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.buffer.detach(&self.cursor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{RingBufConsumer, RingBufProducer, TryDequeueError};
+
+    #[test]
+    fn test_every_subscriber_sees_every_item() {
+        let count = 1000;
+        let (p, c) = super::make::<String>(8);
+        let handles = (0..3)
+            .map(|_| {
+                let c = c.clone();
+                thread::spawn(move || {
+                    let mut expect = 0;
+                    loop {
+                        match c.try_dequeue() {
+                            Ok(v) => {
+                                assert_eq!(v, expect.to_string());
+                                expect += 1;
+                            }
+                            Err(TryDequeueError::Empty) => thread::yield_now(),
+                            Err(TryDequeueError::Disconnected) => break expect,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(c);
+        for i in 0..count {
+            while !p.enqueue(i.to_string()) {
+                thread::yield_now();
+            }
+        }
+        drop(p);
+        for h in handles {
+            assert_eq!(h.join().unwrap(), count);
+        }
+    }
+
+    #[test]
+    fn test_gated_by_slowest() {
+        let (p, fast) = super::make::<u32>(4);
+        let slow = fast.clone();
+        for i in 0..4 {
+            assert!(p.enqueue(i));
+        }
+        for i in 0..4 {
+            assert_eq!(fast.dequeue(), Some(i));
+        }
+        // 速い購読者が読み終えても遅い購読者が読むまでは書けない
+        assert!(p.try_enqueue(4).unwrap_err().is_full());
+        assert_eq!(slow.dequeue(), Some(0));
+        assert!(p.enqueue(4));
+        assert!(!p.enqueue(5));
+        // 遅い購読者を外すと待たなくてよくなる
+        drop(slow);
+        assert!(p.enqueue(5));
+        assert_eq!(p.subscribers(), 1);
+    }
+
+    #[test]
+    fn test_attach_detach() {
+        let (p, c) = super::make::<u32>(4);
+        drop(c);
+        // 購読者がいなくても書ける
+        for i in 0..10 {
+            assert!(p.enqueue(i));
+        }
+        let c = p.subscribe();
+        assert!(c.is_empty());
+        assert!(p.enqueue(10));
+        let late = p.subscribe();
+        assert!(p.enqueue(11));
+        assert_eq!(c.dequeue(), Some(10));
+        assert_eq!(c.dequeue(), Some(11));
+        assert_eq!(late.dequeue(), Some(11));
+        assert_eq!(late.dequeue(), None);
+        drop(p);
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+    }
+}
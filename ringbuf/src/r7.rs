@@ -0,0 +1,339 @@
+//! SPSC ring in overwrite mode. `enqueue` always succeeds, the oldest item is overwritten when
+//! the ring is full. Each slot is guarded by a sequence number like a seqlock, so the consumer
+//! never returns a torn item and reports how many items it has lost.
+//!
+//! Items are copied in and out while the producer may be writing the same slot,
+//! so only `T: Copy` is allowed.
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    error::{TryDequeueError, TryDequeueLossyError, TryEnqueueError},
+    helper::{CachePadded, RingBufConsumer, RingBufProducer},
+    storage::Allocated,
+};
+
+struct Slot<T> {
+    // 2*pos+1: posを書き込み中, 2*pos+2: posを書き込み済み
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+#[inline]
+fn writing_seq(pos: usize) -> usize {
+    pos.wrapping_mul(2).wrapping_add(1)
+}
+
+#[inline]
+fn written_seq(pos: usize) -> usize {
+    pos.wrapping_mul(2).wrapping_add(2)
+}
+
+#[repr(C)]
+pub struct Buffer<T: Copy> {
+    slots: Allocated<Slot<T>>,
+    capacity: usize,
+    position_mask: usize,
+    producer_closed: AtomicBool,
+    write_idx: CachePadded<AtomicUsize>,
+    // Consumerだけが触る。Producerは読み出し位置を見ずに上書きする
+    read_idx: CachePadded<Cell<usize>>,
+}
+unsafe impl<T: Copy + Send> Sync for Buffer<T> {}
+
+/// Not `Sync`, the read index is not shared between threads.
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<ringbuf::r7::Consumer<u32>>();
+/// ```
+pub struct Consumer<T: Copy> {
+    buffer: Arc<Buffer<T>>,
+    // &selfから読むので、Syncにすると複数スレッドから同時に読めてしまう
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// Not `Sync`, two writers on the same slot would break the sequence numbers.
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<ringbuf::r7::Producer<u32>>();
+/// ```
+pub struct Producer<T: Copy> {
+    buffer: Arc<Buffer<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+unsafe impl<T: Copy + Send> Send for Consumer<T> {}
+unsafe impl<T: Copy + Send> Send for Producer<T> {}
+
+impl<T: Copy> Buffer<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let len = capacity.next_power_of_two();
+        let slots = Allocated::<Slot<T>>::heap(len);
+        for i in 0..len {
+            // 0 はどのposの書き込み済みとも一致しない
+            unsafe {
+                ptr::write(
+                    slots.as_ptr().add(i),
+                    Slot {
+                        seq: AtomicUsize::new(0),
+                        value: UnsafeCell::new(MaybeUninit::uninit()),
+                    },
+                );
+            }
+        }
+        Self {
+            slots,
+            capacity,
+            position_mask: len - 1,
+            producer_closed: AtomicBool::new(false),
+            write_idx: CachePadded::default(),
+            read_idx: CachePadded::default(),
+        }
+    }
+
+    #[inline]
+    fn slot(&self, pos: usize) -> &Slot<T> {
+        unsafe { &*self.slots.as_ptr().add(pos & self.position_mask) }
+    }
+
+    /// Must be called only from the producer, which the `Producer` handle guarantees.
+    /// Overwrites the oldest item when the ring is full.
+    fn enqueue(&self, item: T) {
+        let pos = self.write_idx.load(Ordering::Relaxed);
+        let slot = self.slot(pos);
+        slot.seq.store(writing_seq(pos), Ordering::Relaxed);
+        // 奇数のseqが値の書き込みより先に見えるようにする
+        fence(Ordering::Release);
+        unsafe {
+            ptr::write_volatile(slot.value.get(), MaybeUninit::new(item));
+        }
+        slot.seq.store(written_seq(pos), Ordering::Release);
+        self.write_idx.store(pos.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Must be called only from the consumer, which the `Consumer` handle guarantees.
+    fn try_dequeue_lossy(&self) -> Result<T, TryDequeueLossyError> {
+        let pos = self.read_idx.get();
+        let write_idx = self.write_idx.load(Ordering::Acquire);
+        if write_idx == pos {
+            return Err(TryDequeueLossyError::Empty);
+        }
+        if self.capacity < write_idx.wrapping_sub(pos) {
+            return Err(self.skip_to(pos, write_idx));
+        }
+
+        let slot = self.slot(pos);
+        let seq = written_seq(pos);
+        if slot.seq.load(Ordering::Acquire) == seq {
+            // 書き込み途中の値を読むかもしれないので、seqを再確認するまでTとして扱わない
+            let v = unsafe { ptr::read_volatile(slot.value.get()) };
+            fence(Ordering::Acquire);
+            if slot.seq.load(Ordering::Relaxed) == seq {
+                self.read_idx.set(pos.wrapping_add(1));
+                return Ok(unsafe { v.assume_init() });
+            }
+        }
+        // write_idxを読んだ時点で書き込み済みだったので、seqが違うなら次の周回に上書きされている
+        Err(self.skip_to(pos, self.write_idx.load(Ordering::Acquire)))
+    }
+
+    /// Moves `read_idx` to the oldest item which is not overwritten yet.
+    fn skip_to(&self, pos: usize, write_idx: usize) -> TryDequeueLossyError {
+        let oldest = if self.capacity < write_idx.wrapping_sub(pos) {
+            write_idx.wrapping_sub(self.capacity)
+        } else {
+            pos.wrapping_add(1)
+        };
+        self.read_idx.set(oldest);
+        TryDequeueLossyError::Lagged(oldest.wrapping_sub(pos))
+    }
+}
+
+/// The buffer is reachable only through the handles, so there is a single writer.
+pub fn make<T: Copy>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let arc = Arc::new(Buffer::with_capacity(capacity));
+
+    (
+        Producer {
+            buffer: arc.clone(),
+            _not_sync: PhantomData,
+        },
+        Consumer {
+            buffer: arc,
+            _not_sync: PhantomData,
+        },
+    )
+}
+
+impl<T: Copy> RingBufProducer<T> for Producer<T> {
+    /// Never fails with `Full`, the oldest item is overwritten instead.
+    fn try_enqueue(&self, item: T) -> Result<(), TryEnqueueError<T>> {
+        if self.is_closed() {
+            return Err(TryEnqueueError::Disconnected(item));
+        }
+        self.buffer.enqueue(item);
+        Ok(())
+    }
+}
+
+impl<T: Copy> RingBufConsumer<T> for Consumer<T> {
+    /// Skips overwritten items silently, use `try_dequeue_lossy` to know how many are lost.
+    fn try_dequeue(&self) -> Result<T, TryDequeueError> {
+        loop {
+            match self.try_dequeue_lossy() {
+                Ok(v) => return Ok(v),
+                Err(TryDequeueLossyError::Lagged(_)) => {}
+                Err(TryDequeueLossyError::Empty) => return Err(TryDequeueError::Empty),
+                Err(TryDequeueLossyError::Disconnected) => {
+                    return Err(TryDequeueError::Disconnected)
+                }
+            }
+        }
+    }
+}
+
+impl<T: Copy> Producer<T> {
+    /// Marks the ring as closed. The consumer gets `Disconnected` once the remaining items are
+    /// drained. Dropping the producer closes the ring as well.
+    pub fn close(&self) {
+        self.buffer.producer_closed.store(true, Ordering::Release);
+    }
+
+    /// `true` after `close`. The consumer being dropped does not close the ring,
+    /// the producer keeps overwriting.
+    pub fn is_closed(&self) -> bool {
+        self.buffer.producer_closed.load(Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+}
+
+impl<T: Copy> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T: Copy> Consumer<T> {
+    /// Dequeues the oldest item which is not overwritten yet.
+    /// Returns `Lagged(n)` once when `n` unread items have been overwritten since the last call.
+    pub fn try_dequeue_lossy(&self) -> Result<T, TryDequeueLossyError> {
+        match self.buffer.try_dequeue_lossy() {
+            Err(TryDequeueLossyError::Empty) if self.is_closed() => {
+                // close前に書かれた要素を取りこぼさないように読み直す
+                match self.buffer.try_dequeue_lossy() {
+                    Err(TryDequeueLossyError::Empty) => Err(TryDequeueLossyError::Disconnected),
+                    r => r,
+                }
+            }
+            r => r,
+        }
+    }
+
+    /// `true` when the producer has closed the ring.
+    /// Items enqueued before the close can still be dequeued.
+    pub fn is_closed(&self) -> bool {
+        self.buffer.producer_closed.load(Ordering::Acquire)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    /// Number of readable items, at most `capacity`.
+    pub fn len(&self) -> usize {
+        let read_idx = self.buffer.read_idx.get();
+        self.buffer
+            .write_idx
+            .load(Ordering::Acquire)
+            .wrapping_sub(read_idx)
+            .min(self.capacity())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::{RingBufConsumer, RingBufProducer, TryDequeueLossyError};
+
+    #[test]
+    fn test_lagged() {
+        for cap in [3, 4] {
+            let (p, c) = super::make::<u32>(cap);
+            for i in 0..10 {
+                assert!(p.enqueue(i));
+            }
+            assert_eq!(c.len(), cap);
+            assert_eq!(
+                c.try_dequeue_lossy(),
+                Err(TryDequeueLossyError::Lagged(10 - cap))
+            );
+            for i in (10 - cap as u32)..10 {
+                assert_eq!(c.try_dequeue_lossy(), Ok(i));
+            }
+            assert_eq!(c.try_dequeue_lossy(), Err(TryDequeueLossyError::Empty));
+
+            // try_dequeueは失った分を黙って読み飛ばす
+            for i in 10..20 {
+                assert!(p.enqueue(i));
+            }
+            assert_eq!(c.dequeue(), Some(20 - cap as u32));
+            drop(p);
+            for i in (21 - cap as u32)..20 {
+                assert_eq!(c.try_dequeue_lossy(), Ok(i));
+            }
+            assert_eq!(
+                c.try_dequeue_lossy(),
+                Err(TryDequeueLossyError::Disconnected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_torn_reads() {
+        let count = 200_000_u64;
+        let (p, c) = super::make::<[u64; 16]>(8);
+        let h = thread::spawn(move || {
+            for i in 1..=count {
+                assert!(p.enqueue([i; 16]));
+            }
+        });
+
+        let (mut received, mut lost, mut last) = (0, 0, 0);
+        loop {
+            match c.try_dequeue_lossy() {
+                Ok(v) => {
+                    // 全ての要素が同じ書き込みのものでなければならない
+                    assert!(v.iter().all(|x| *x == v[0]), "torn read {:?}", v);
+                    assert!(last < v[0]);
+                    last = v[0];
+                    received += 1;
+                }
+                Err(TryDequeueLossyError::Lagged(n)) => lost += n as u64,
+                Err(TryDequeueLossyError::Empty) => std::hint::spin_loop(),
+                Err(TryDequeueLossyError::Disconnected) => break,
+            }
+        }
+        h.join().unwrap();
+        assert_eq!(received + lost, count);
+        assert_eq!(last, count);
+    }
+}
@@ -0,0 +1,301 @@
+//! SPSC ring whose `N` slots are an inline array, same algorithm as r3.
+//! Needs no allocation, `Buffer::new` is a `const fn` so the ring can be a `static`.
+//! The handles borrow the buffer, see [`Buffer::split`] and, for a `static`, [`Buffer::try_split`].
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::{
+    error::{Empty, Full, TryDequeueError, TryEnqueueError},
+    helper::{CachePadded, RingBufConsumer, RingBufProducer},
+};
+
+/// `N` must be a power of two, otherwise `new` fails to compile.
+pub struct Buffer<T, const N: usize> {
+    // PCで別のスレッドが触るので個別のcachelineに乗るようにalignして分割する
+    producer: CachePadded<ProducerSide>,
+    consumer: CachePadded<ConsumerSide>,
+    // try_splitでハンドルを渡したか。&selfから2組目を作らせない
+    taken: AtomicBool,
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+}
+// Cellはsplitで分けたハンドルの片側からしか触らない
+unsafe impl<T: Send, const N: usize> Sync for Buffer<T, N> {}
+
+/// Written by the producer, read by the consumer only to refresh its cache.
+struct ProducerSide {
+    write_idx: AtomicUsize,
+    cached_read_idx: Cell<usize>,
+}
+
+struct ConsumerSide {
+    read_idx: AtomicUsize,
+    cached_write_idx: Cell<usize>,
+}
+
+pub struct Producer<'a, T, const N: usize> {
+    buffer: &'a Buffer<T, N>,
+    // 別のスレッドへ送れるが、共有はできない
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+pub struct Consumer<'a, T, const N: usize> {
+    buffer: &'a Buffer<T, N>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T, const N: usize> Buffer<T, N> {
+    const POWER_OF_TWO: () = assert!(N.is_power_of_two(), "N must be a power of two");
+
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::POWER_OF_TWO;
+        Self {
+            producer: CachePadded::new(ProducerSide {
+                write_idx: AtomicUsize::new(0),
+                cached_read_idx: Cell::new(0),
+            }),
+            consumer: CachePadded::new(ConsumerSide {
+                read_idx: AtomicUsize::new(0),
+                cached_write_idx: Cell::new(0),
+            }),
+            taken: AtomicBool::new(false),
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+        }
+    }
+
+    /// Splits the ring into the producer and the consumer. Items left when the handles are
+    /// dropped stay in the ring, and the next split continues from them.
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        self.handles()
+    }
+
+    /// Same as [`split`](Self::split) but through a shared reference, so a `static` ring can be
+    /// split. Only the first call returns the handles, later calls return `None`.
+    pub fn try_split(&self) -> Option<(Producer<'_, T, N>, Consumer<'_, T, N>)> {
+        if self.taken.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(self.handles())
+    }
+
+    fn handles(&self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (
+            Producer {
+                buffer: self,
+                _not_sync: PhantomData,
+            },
+            Consumer {
+                buffer: self,
+                _not_sync: PhantomData,
+            },
+        )
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        let read_idx = self.consumer.read_idx.load(Ordering::Acquire);
+        self.producer
+            .write_idx
+            .load(Ordering::Acquire)
+            .wrapping_sub(read_idx)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    fn slot(&self, pos: usize) -> *mut MaybeUninit<T> {
+        self.slots[pos & (N - 1)].get()
+    }
+
+    fn try_enqueue(&self, item: T) -> Result<(), Full<T>> {
+        let write_idx = self.producer.write_idx.load(Ordering::Relaxed);
+        if self.producer.cached_read_idx.get().wrapping_add(N) == write_idx {
+            self.producer
+                .cached_read_idx
+                .set(self.consumer.read_idx.load(Ordering::Acquire));
+            if self.producer.cached_read_idx.get().wrapping_add(N) == write_idx {
+                return Err(Full(item));
+            }
+        }
+
+        unsafe {
+            (*self.slot(write_idx)).write(item);
+        }
+        self.producer
+            .write_idx
+            .store(write_idx.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    fn try_dequeue(&self) -> Result<T, Empty> {
+        let read_idx = self.consumer.read_idx.load(Ordering::Relaxed);
+        if self.consumer.cached_write_idx.get() == read_idx {
+            self.consumer
+                .cached_write_idx
+                .set(self.producer.write_idx.load(Ordering::Acquire));
+            if self.consumer.cached_write_idx.get() == read_idx {
+                return Err(Empty);
+            }
+        }
+
+        let v = unsafe { (*self.slot(read_idx)).assume_init_read() };
+        self.consumer
+            .read_idx
+            .store(read_idx.wrapping_add(1), Ordering::Release);
+        Ok(v)
+    }
+}
+
+impl<T, const N: usize> Default for Buffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Buffer<T, N> {
+    fn drop(&mut self) {
+        while self.try_dequeue().is_ok() {}
+    }
+}
+
+impl<T, const N: usize> RingBufProducer<T> for Producer<'_, T, N> {
+    /// Never fails with `Disconnected`, the consumer can not be gone while the buffer is borrowed.
+    fn try_enqueue(&self, item: T) -> Result<(), TryEnqueueError<T>> {
+        self.buffer.try_enqueue(item).map_err(Into::into)
+    }
+}
+
+impl<T, const N: usize> RingBufConsumer<T> for Consumer<'_, T, N> {
+    fn try_dequeue(&self) -> Result<T, TryDequeueError> {
+        self.buffer.try_dequeue().map_err(Into::into)
+    }
+}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::Buffer;
+    use crate::{helper::CACHELINE_LEN, RingBufConsumer, RingBufProducer};
+
+    // constで作れるのでstaticに置ける
+    static STATIC: Buffer<u64, 1024> = Buffer::new();
+
+    #[test]
+    fn test_layout() {
+        let buf = Buffer::<u8, 4>::new();
+        let producer = &buf.producer.write_idx as *const _ as usize;
+        let consumer = &buf.consumer.read_idx as *const _ as usize;
+        // 両側のindexがそれぞれ行の先頭にあり、同じ行に乗らない
+        assert_eq!(producer % CACHELINE_LEN, 0);
+        assert_eq!(consumer % CACHELINE_LEN, 0);
+        assert!(producer + CACHELINE_LEN <= consumer);
+    }
+
+    #[test]
+    fn test_split() {
+        let mut buf = Buffer::<String, 4>::new();
+        {
+            let (p, c) = buf.split();
+            for i in 0..6 {
+                assert!(p.enqueue(i.to_string()));
+                assert_eq!(c.dequeue(), Some(i.to_string()));
+            }
+            assert!(p.enqueue("left".to_string()));
+        }
+        // 残った要素は次のsplitで読める
+        let (p, c) = buf.split();
+        assert_eq!(p.len(), 1);
+        assert_eq!(c.dequeue(), Some("left".to_string()));
+        assert!(p.enqueue("dropped with the buffer".to_string()));
+    }
+
+    #[test]
+    fn test_try_split_static() {
+        let (p, c) = STATIC.try_split().unwrap();
+        assert!(STATIC.try_split().is_none());
+        // 'staticなハンドルはscopeなしで別スレッドへ送れる
+        let h = thread::spawn(move || {
+            for i in 0..2048 {
+                while !p.enqueue(i) {
+                    thread::yield_now();
+                }
+            }
+        });
+        for i in 0..2048 {
+            loop {
+                if let Some(v) = c.dequeue() {
+                    assert_eq!(v, i);
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+        h.join().unwrap();
+        assert!(STATIC.is_empty());
+    }
+
+    #[test]
+    fn test_multi_thread() {
+        let count = 100_000;
+        let mut buf = Buffer::<usize, 64>::new();
+        let (p, c) = buf.split();
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..count {
+                    while !p.enqueue(i) {
+                        thread::yield_now();
+                    }
+                }
+            });
+            for i in 0..count {
+                loop {
+                    if let Some(v) = c.dequeue() {
+                        assert_eq!(v, i);
+                        break;
+                    }
+                    thread::yield_now();
+                }
+            }
+        });
+        assert!(buf.is_empty());
+    }
+}
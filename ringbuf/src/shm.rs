@@ -0,0 +1,434 @@
+//! SPSC ring between processes on a POSIX shared memory object (`/dev/shm/<name>`),
+//! same algorithm as r3.
+//!
+//! The region starts with a header recording its layout, so [`Region::open`] rejects a region
+//! made for another element type or by an incompatible version, and the slots follow it.
+//! Only plain-old-data can be put in the ring, see [`Pod`].
+
+use std::{
+    cell::Cell,
+    ffi::CString,
+    io::{self, ErrorKind},
+    marker::PhantomData,
+    mem, ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use crate::{
+    error::{Empty, Full, TryDequeueError, TryEnqueueError},
+    helper::{dequeue_or_disconnected, RingBufConsumer, RingBufProducer},
+};
+
+/// Types which can be copied to another process byte by byte.
+///
+/// # Safety
+/// The type must have a stable layout (a primitive, an array of them or `#[repr(C)]`),
+/// contain no pointers or references, and accept any bit pattern.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+const MAGIC: u64 = u64::from_le_bytes(*b"RBUFSHM\0");
+
+/// Incremented whenever the layout of the region changes.
+pub const VERSION: u32 = 1;
+
+// 32bitと64bitのプロセスの間でも同じ配置になるように固定長の型だけを使う
+#[repr(C)]
+struct Header {
+    // 他のフィールドを書き終えてから最後に書く
+    magic: AtomicU64,
+    version: u32,
+    elem_size: u32,
+    elem_align: u32,
+    _reserved: u32,
+    capacity: u64,
+    slots: u64,
+    // 片側のハンドルがdropされたことを相手側のプロセスに伝える
+    producer_closed: AtomicU32,
+    consumer_closed: AtomicU32,
+    _padding0: [u64; 2],
+    write_idx: AtomicU64,
+    _padding1: [u64; 7],
+    read_idx: AtomicU64,
+    _padding2: [u64; 7],
+}
+
+// slotsはHeaderの直後から始まる
+const HEADER_LEN: usize = mem::size_of::<Header>();
+const _: () = assert!(HEADER_LEN.is_multiple_of(64));
+
+fn map_len<T>(slots: usize) -> Option<usize> {
+    slots
+        .checked_mul(mem::size_of::<T>())?
+        .checked_add(HEADER_LEN)
+}
+
+fn check_layout<T>() -> io::Result<()> {
+    if mem::size_of::<T>() == 0 || 64 < mem::align_of::<T>() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "element must be non zero-sized and aligned to at most 64 bytes",
+        ));
+    }
+    Ok(())
+}
+
+fn c_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+unsafe fn map(fd: libc::c_int, len: usize) -> io::Result<*mut u8> {
+    let p = libc::mmap(
+        ptr::null_mut(),
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED,
+        fd,
+        0,
+    );
+    if p == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(p as *mut u8)
+}
+
+/// A mapping of the shared memory object. Turn it into the handle of this process with
+/// [`Region::into_producer`] or [`Region::into_consumer`].
+///
+/// Nothing prevents two processes from taking the same side, that is up to the caller.
+pub struct Region<T: Pod> {
+    header: *mut Header,
+    map_len: usize,
+    // createしたプロセスだけがdropで名前を消す
+    owned_name: Option<CString>,
+    _marker: PhantomData<T>,
+}
+unsafe impl<T: Pod + Send> Send for Region<T> {}
+
+impl<T: Pod> Region<T> {
+    /// Creates the object `name` (e.g. `/ringbuf`) with room for `capacity` items.
+    /// Fails when it already exists. The name is removed when the region is dropped,
+    /// processes which have opened it keep their mapping.
+    pub fn create(name: &str, capacity: usize) -> io::Result<Self> {
+        check_layout::<T>()?;
+        let slots = capacity.next_power_of_two();
+        let map_len = map_len::<T>(slots)
+            .filter(|len| isize::try_from(*len).is_ok())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "capacity overflow"))?;
+        let cname = c_name(name)?;
+
+        unsafe {
+            let fd = libc::shm_open(
+                cname.as_ptr(),
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                0o600,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mapped = if libc::ftruncate(fd, map_len as libc::off_t) == 0 {
+                map(fd, map_len)
+            } else {
+                Err(io::Error::last_os_error())
+            };
+            // mapが残っている間はfdを閉じても領域は消えない
+            libc::close(fd);
+            let header = match mapped {
+                Ok(p) => p as *mut Header,
+                Err(e) => {
+                    libc::shm_unlink(cname.as_ptr());
+                    return Err(e);
+                }
+            };
+
+            ptr::write(
+                header,
+                Header {
+                    magic: AtomicU64::new(0),
+                    version: VERSION,
+                    elem_size: mem::size_of::<T>() as u32,
+                    elem_align: mem::align_of::<T>() as u32,
+                    _reserved: 0,
+                    capacity: capacity as u64,
+                    slots: slots as u64,
+                    producer_closed: AtomicU32::new(0),
+                    consumer_closed: AtomicU32::new(0),
+                    _padding0: [0; 2],
+                    write_idx: AtomicU64::new(0),
+                    _padding1: [0; 7],
+                    read_idx: AtomicU64::new(0),
+                    _padding2: [0; 7],
+                },
+            );
+            (*header).magic.store(MAGIC, Ordering::Release);
+
+            Ok(Self {
+                header,
+                map_len,
+                owned_name: Some(cname),
+                _marker: PhantomData,
+            })
+        }
+    }
+
+    /// Opens the object `name` made by [`Region::create`] with the same `T`.
+    /// Fails with `InvalidData` when the layout recorded in it does not match.
+    pub fn open(name: &str) -> io::Result<Self> {
+        check_layout::<T>()?;
+        let cname = c_name(name)?;
+
+        unsafe {
+            let fd = libc::shm_open(cname.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut stat = mem::zeroed::<libc::stat>();
+            let mapped = if libc::fstat(fd, &mut stat) != 0 {
+                Err(io::Error::last_os_error())
+            } else if (stat.st_size as u64) < HEADER_LEN as u64 {
+                Err(invalid_data("region is smaller than the header"))
+            } else {
+                map(fd, stat.st_size as usize).map(|p| (p, stat.st_size as usize))
+            };
+            libc::close(fd);
+            let (p, map_len) = mapped?;
+
+            let region = Self {
+                header: p as *mut Header,
+                map_len,
+                owned_name: None,
+                _marker: PhantomData,
+            };
+            region.validate()?;
+            Ok(region)
+        }
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        let h = self.header();
+        if h.magic.load(Ordering::Acquire) != MAGIC {
+            return Err(invalid_data("not a ring, or not initialized yet"));
+        }
+        if h.version != VERSION {
+            return Err(invalid_data("created by an incompatible version"));
+        }
+        if h.elem_size as usize != mem::size_of::<T>()
+            || h.elem_align as usize != mem::align_of::<T>()
+        {
+            return Err(invalid_data("created for another element type"));
+        }
+        let slots = usize::try_from(h.slots).map_err(|_| invalid_data("too many slots"))?;
+        if !slots.is_power_of_two() || slots < self.capacity() as usize {
+            return Err(invalid_data("broken capacity"));
+        }
+        if map_len::<T>(slots).is_none_or(|len| self.map_len < len) {
+            return Err(invalid_data("region is smaller than the slots"));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    #[inline]
+    unsafe fn slot(&self, pos: u64) -> *mut T {
+        let slots = (self.header as *mut u8).add(HEADER_LEN) as *mut T;
+        slots.add((pos & (self.header().slots - 1)) as usize)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.header().capacity
+    }
+
+    pub fn into_producer(self) -> Producer<T> {
+        Producer {
+            region: self,
+            cached_read_idx: Cell::new(0),
+        }
+    }
+
+    pub fn into_consumer(self) -> Consumer<T> {
+        let read_idx = self.header().read_idx.load(Ordering::Relaxed);
+        Consumer {
+            region: self,
+            cached_write_idx: Cell::new(read_idx),
+        }
+    }
+}
+
+impl<T: Pod> Drop for Region<T> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.header as *mut libc::c_void, self.map_len);
+            if let Some(name) = &self.owned_name {
+                libc::shm_unlink(name.as_ptr());
+            }
+        }
+    }
+}
+
+/// The producing side in this process.
+pub struct Producer<T: Pod> {
+    region: Region<T>,
+    // プロセスごとに持つので共有領域には置かない
+    cached_read_idx: Cell<u64>,
+}
+
+/// The consuming side in this process.
+pub struct Consumer<T: Pod> {
+    region: Region<T>,
+    cached_write_idx: Cell<u64>,
+}
+
+impl<T: Pod> RingBufProducer<T> for Producer<T> {
+    fn try_enqueue(&self, item: T) -> Result<(), TryEnqueueError<T>> {
+        if self.is_closed() {
+            return Err(TryEnqueueError::Disconnected(item));
+        }
+        self.try_push(item).map_err(Into::into)
+    }
+}
+
+impl<T: Pod> RingBufConsumer<T> for Consumer<T> {
+    fn try_dequeue(&self) -> Result<T, TryDequeueError> {
+        dequeue_or_disconnected(|| self.try_pop(), || self.is_closed())
+    }
+}
+
+impl<T: Pod> Producer<T> {
+    fn try_push(&self, item: T) -> Result<(), Full<T>> {
+        let h = self.region.header();
+        let write_idx = h.write_idx.load(Ordering::Relaxed);
+        let capacity = self.region.capacity();
+        if self.cached_read_idx.get() + capacity <= write_idx {
+            self.cached_read_idx.set(h.read_idx.load(Ordering::Acquire));
+            if self.cached_read_idx.get() + capacity <= write_idx {
+                return Err(Full(item));
+            }
+        }
+
+        unsafe {
+            ptr::write(self.region.slot(write_idx), item);
+        }
+        h.write_idx.store(write_idx + 1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Marks the ring as closed. The consumer gets `Disconnected` once the remaining items are
+    /// drained. Dropping the producer closes the ring as well.
+    pub fn close(&self) {
+        self.region
+            .header()
+            .producer_closed
+            .store(1, Ordering::Release);
+    }
+
+    /// `true` when either side has closed the ring.
+    pub fn is_closed(&self) -> bool {
+        let h = self.region.header();
+        h.consumer_closed.load(Ordering::Acquire) != 0
+            || h.producer_closed.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.region.capacity() as usize
+    }
+}
+
+impl<T: Pod> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T: Pod> Consumer<T> {
+    fn try_pop(&self) -> Result<T, Empty> {
+        let h = self.region.header();
+        let read_idx = h.read_idx.load(Ordering::Relaxed);
+        if self.cached_write_idx.get() == read_idx {
+            self.cached_write_idx
+                .set(h.write_idx.load(Ordering::Acquire));
+            if self.cached_write_idx.get() == read_idx {
+                return Err(Empty);
+            }
+        }
+
+        let v = unsafe { ptr::read(self.region.slot(read_idx)) };
+        h.read_idx.store(read_idx + 1, Ordering::Release);
+        Ok(v)
+    }
+
+    /// `true` when the producer has closed the ring.
+    /// Items enqueued before the close can still be dequeued.
+    pub fn is_closed(&self) -> bool {
+        self.region.header().producer_closed.load(Ordering::Acquire) != 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.region.capacity() as usize
+    }
+}
+
+impl<T: Pod> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.region
+            .header()
+            .consumer_closed
+            .store(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::Region;
+    use crate::{RingBufConsumer, RingBufProducer, TryDequeueError};
+
+    #[test]
+    fn test_open() {
+        let name = format!("/ringbuf-test-open-{}", std::process::id());
+        let created = Region::<u32>::create(&name, 5).unwrap();
+        assert_eq!(
+            Region::<u32>::create(&name, 5).err().unwrap().kind(),
+            ErrorKind::AlreadyExists
+        );
+        // 要素の型が違うと開けない
+        assert_eq!(
+            Region::<u64>::open(&name).err().unwrap().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let p = created.into_producer();
+        let c = Region::<u32>::open(&name).unwrap().into_consumer();
+        assert_eq!(c.capacity(), 5);
+        for i in 0..5 {
+            assert!(p.enqueue(i));
+        }
+        assert!(!p.enqueue(5));
+        drop(p);
+        for i in 0..5 {
+            assert_eq!(c.try_dequeue(), Ok(i));
+        }
+        assert_eq!(c.try_dequeue(), Err(TryDequeueError::Disconnected));
+
+        // createした側がdropされると名前は消える
+        assert_eq!(
+            Region::<u32>::open(&name).err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Memory for the slots of a ring.
+//!
+//! The rings only read and write the slots, allocating and releasing them is left to a
+//! [`Storage`]. [`Allocated`] owns memory from the heap, huge pages or a mirrored mapping,
+//! a `&'static mut` or boxed slice of `MaybeUninit<T>` can be used as is, and [`Raw`] wraps
+//! memory handed over by someone else such as an `mmap` region.
+
+use core::{mem::MaybeUninit, ptr::NonNull};
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+
+use crate::helper::Backing;
+#[cfg(feature = "alloc")]
+use crate::{
+    helper::{allocate_buffer, allocate_exact, deallocate_buffer},
+    hugepage::allocate_huge,
+    mirror::allocate_mirrored,
+};
+
+/// Memory for the slots of a ring.
+///
+/// The handles of the rings shared by `Arc` are `Send` only when the storage is `Send`,
+/// the thread that drops the last handle drops the storage.
+///
+/// ```compile_fail
+/// use std::{mem::MaybeUninit, rc::Rc};
+///
+/// struct Local(Rc<()>, Box<[MaybeUninit<u32>]>);
+///
+/// unsafe impl ringbuf::Storage<u32> for Local {
+///     fn as_mut_ptr(&mut self) -> *mut u32 {
+///         self.1.as_mut_ptr().cast()
+///     }
+///
+///     fn slots(&self) -> usize {
+///         self.1.len()
+///     }
+/// }
+///
+/// fn assert_send<T: Send>(_: T) {}
+/// let storage = Local(Rc::new(()), Box::new([MaybeUninit::uninit(); 4]));
+/// let buf = ringbuf::r3::Buffer::with_storage_and_wait(storage, 4, ringbuf::wait::BusySpin);
+/// assert_send(ringbuf::r3::from_buffer(buf).0);
+/// ```
+///
+/// # Safety
+/// The pointer returned by `as_mut_ptr` must be valid for reads and writes of `slots()`
+/// elements of `T` until the storage is dropped, must not move when the storage is moved,
+/// and must not be accessed by anything but the ring meanwhile. The ring uses the slots
+/// from every thread its handles are sent to. When `backing()` is [`Backing::Mirrored`]
+/// `slots()` must be a power of two and the `slots()` elements after them must be the same
+/// memory again.
+pub unsafe trait Storage<T> {
+    /// Head of the slots. Called once when the ring is built.
+    fn as_mut_ptr(&mut self) -> *mut T;
+
+    /// Number of slots.
+    fn slots(&self) -> usize;
+
+    /// Where the slots are allocated.
+    fn backing(&self) -> Backing {
+        Backing::External
+    }
+}
+
+/// Memory allocated by this crate and released when dropped.
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct Allocated<T> {
+    ptr: NonNull<T>,
+    len: usize,
+    backing: Backing,
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl<T: Send> Send for Allocated<T> {}
+#[cfg(feature = "alloc")]
+unsafe impl<T: Sync> Sync for Allocated<T> {}
+
+#[cfg(feature = "alloc")]
+impl<T> Allocated<T> {
+    /// `capacity.next_power_of_two()` slots from the global allocator.
+    pub fn heap(capacity: usize) -> Self {
+        let ptr = unsafe { allocate_buffer(capacity) };
+        Self::from_parts(ptr, capacity.next_power_of_two(), Backing::Heap)
+    }
+
+    /// Exactly `len` slots from the global allocator.
+    pub fn exact(len: usize) -> Self {
+        let ptr = unsafe { allocate_exact(len) };
+        Self::from_parts(ptr, len, Backing::Heap)
+    }
+
+    /// At least `capacity.next_power_of_two()` slots on huge pages when possible,
+    /// see [`crate::hugepage::allocate_huge`].
+    pub fn huge(capacity: usize) -> Self {
+        let (ptr, len, backing) = unsafe { allocate_huge(capacity) };
+        Self::from_parts(ptr, len, backing)
+    }
+
+    /// At least `capacity.next_power_of_two()` slots mapped twice,
+    /// or `None` when the mapping is not possible, see [`crate::mirror::allocate_mirrored`].
+    pub fn mirrored(capacity: usize) -> Option<Self> {
+        let (ptr, len) = unsafe { allocate_mirrored(capacity) }?;
+        Some(Self::from_parts(ptr, len, Backing::Mirrored))
+    }
+
+    /// Head of the slots.
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    fn from_parts(ptr: *mut T, len: usize, backing: Backing) -> Self {
+        Self {
+            ptr: NonNull::new(ptr).expect("allocation returned null"),
+            len,
+            backing,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl<T> Storage<T> for Allocated<T> {
+    fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    fn slots(&self) -> usize {
+        self.len
+    }
+
+    fn backing(&self) -> Backing {
+        self.backing
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<T> Drop for Allocated<T> {
+    fn drop(&mut self) {
+        unsafe { deallocate_buffer(self.ptr.as_ptr(), self.len, self.backing) };
+    }
+}
+
+unsafe impl<T> Storage<T> for &'static mut [MaybeUninit<T>] {
+    fn as_mut_ptr(&mut self) -> *mut T {
+        (**self).as_mut_ptr() as *mut T
+    }
+
+    fn slots(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl<T> Storage<T> for Box<[MaybeUninit<T>]> {
+    fn as_mut_ptr(&mut self) -> *mut T {
+        (**self).as_mut_ptr() as *mut T
+    }
+
+    fn slots(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Memory owned by someone else, e.g. an `mmap` region. Nothing is released when dropped.
+#[derive(Debug)]
+pub struct Raw<T> {
+    ptr: NonNull<T>,
+    len: usize,
+}
+
+unsafe impl<T: Send> Send for Raw<T> {}
+unsafe impl<T: Sync> Sync for Raw<T> {}
+
+impl<T> Raw<T> {
+    /// # Safety
+    /// `ptr` must satisfy the requirements of [`Storage`] for `len` slots
+    /// as long as the ring built on it is alive.
+    pub unsafe fn new(ptr: NonNull<T>, len: usize) -> Self {
+        Self { ptr, len }
+    }
+}
+
+unsafe impl<T> Storage<T> for Raw<T> {
+    fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    fn slots(&self) -> usize {
+        self.len
+    }
+}
+
+/// Storage the rings use when none is given. Without the `alloc` feature the rings are built
+/// on caller-supplied memory only.
+#[cfg(feature = "alloc")]
+pub type DefaultStorage<T> = Allocated<T>;
+#[cfg(not(feature = "alloc"))]
+pub type DefaultStorage<T> = &'static mut [MaybeUninit<T>];
+
+/// Largest power of two not greater than `slots`, the slots a masked ring can use.
+pub(crate) fn usable_slots(slots: usize) -> usize {
+    if slots == 0 {
+        0
+    } else {
+        1 << (usize::BITS - 1 - slots.leading_zeros())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{r0, r1, r2, r3, wait::BusySpin, RingBufConsumer, RingBufProducer, RingBufTrait};
+
+    fn uninit_slice<T>(len: usize) -> Box<[MaybeUninit<T>]> {
+        (0..len).map(|_| MaybeUninit::uninit()).collect()
+    }
+
+    #[test]
+    fn test_usable_slots() {
+        assert_eq!(usable_slots(0), 0);
+        assert_eq!(usable_slots(1), 1);
+        assert_eq!(usable_slots(7), 4);
+        assert_eq!(usable_slots(8), 8);
+        let mut s = Allocated::<u64>::heap(5);
+        assert_eq!((s.slots(), s.backing()), (8, Backing::Heap));
+        assert!(!s.as_mut_ptr().is_null());
+    }
+
+    #[test]
+    fn test_zero_sized() {
+        // サイズ0の型や長さ0ではallocを呼ばない
+        let s = Allocated::<()>::heap(5);
+        assert_eq!((s.slots(), s.backing()), (8, Backing::Heap));
+        drop(Allocated::<u64>::exact(0));
+        drop(Allocated::<()>::huge(4));
+
+        let mut rb = r0::RingBuf::<()>::with_capacity(4);
+        let mut rb1 = r1::RingBuf::<()>::with_capacity(4);
+        let (p2, c2) = r2::make::<()>(4);
+        let (p3, c3) = r3::make::<()>(4);
+        for _ in 0..10 {
+            assert!(rb.enqueue(()) && rb1.enqueue(()) && p2.enqueue(()) && p3.enqueue(()));
+            assert_eq!(
+                (rb.dequeue(), rb1.dequeue(), c2.dequeue(), c3.dequeue()),
+                (Some(()), Some(()), Some(()), Some(()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_rings_on_storage() {
+        // 残った要素はringのdropで解放され、領域はstorageの持ち主が解放する
+        let mut rb = r0::RingBuf::with_storage(uninit_slice::<String>(3), 3);
+        for i in 0..5 {
+            assert!(rb.enqueue(i.to_string()));
+            assert_eq!(rb.dequeue(), Some(i.to_string()));
+        }
+        assert!(rb.enqueue("left".to_string()));
+        assert_eq!(rb.backing(), Backing::External);
+
+        let mut region = uninit_slice::<String>(6);
+        let raw = unsafe {
+            Raw::new(
+                NonNull::new((*region).as_mut_ptr() as *mut String).unwrap(),
+                6,
+            )
+        };
+        let mut rb = r1::RingBuf::with_storage(raw, 3);
+        for i in 0..10 {
+            assert!(rb.enqueue(i.to_string()));
+            assert_eq!(rb.dequeue(), Some(i.to_string()));
+        }
+        assert!(rb.enqueue("left".to_string()));
+        drop(rb);
+        drop(region);
+
+        let leaked: &'static mut [MaybeUninit<String>] = Box::leak(uninit_slice(4));
+        let (p, c) = r2::from_buffer(r2::Buffer::with_storage_and_wait(leaked, 4, BusySpin));
+        for i in 0..10 {
+            assert!(p.enqueue(i.to_string()));
+            assert_eq!(c.dequeue(), Some(i.to_string()));
+        }
+
+        let buf = r3::Buffer::with_storage_and_wait(uninit_slice::<String>(5), 3, BusySpin);
+        assert_eq!(buf.backing(), Backing::External);
+        let (p, c) = r3::from_buffer(buf);
+        for i in 0..3 {
+            assert!(p.enqueue(i.to_string()));
+        }
+        assert!(!p.enqueue("full".to_string()));
+        assert_eq!(c.dequeue(), Some("0".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "storage is smaller than capacity")]
+    fn test_storage_too_small() {
+        // 2の冪に切り下げた4スロットしか使えない
+        r2::Buffer::with_storage_and_wait(uninit_slice::<u32>(7), 5, BusySpin);
+    }
+
+    struct FakeMirrored(Box<[MaybeUninit<u32>]>);
+
+    unsafe impl Storage<u32> for FakeMirrored {
+        fn as_mut_ptr(&mut self) -> *mut u32 {
+            self.0.as_mut_ptr()
+        }
+
+        fn slots(&self) -> usize {
+            self.0.len()
+        }
+
+        fn backing(&self) -> Backing {
+            Backing::Mirrored
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "mirrored storage must have a power of two slots")]
+    fn test_mirrored_not_power_of_two() {
+        // 4スロットで周回するとミラーされた6スロット目以降と位置がずれる
+        r3::Buffer::with_storage_and_wait(FakeMirrored(uninit_slice(6)), 4, BusySpin);
+    }
+}
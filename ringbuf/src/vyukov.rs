@@ -0,0 +1,293 @@
+//! Slot sequence numbers in the style of Dmitry Vyukov's bounded queue, shared by
+//! [`crate::r4`] and [`crate::r5`]. Producers claim a slot by CAS on `write_idx`, the
+//! rings differ only in how their consumers take a slot.
+
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use crate::{
+    error::{Empty, Full, TryEnqueueError},
+    helper::{CachePadded, RingBufBlockingProducer, RingBufProducer},
+    storage::Allocated,
+    wait::{BusySpin, WaitStrategy},
+};
+
+struct Slot<T> {
+    // pos: 書き込み可能, pos+1: 読み出し可能, pos+slots: 次の周回で書き込み可能
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+#[repr(C)]
+pub struct Buffer<T, W: WaitStrategy = BusySpin> {
+    slots: Allocated<Slot<T>>,
+    pub(crate) capacity: usize,
+    position_mask: usize,
+    // 片側のハンドルがdropされたことを相手側に伝える
+    pub(crate) producer_closed: AtomicBool,
+    pub(crate) consumer_closed: AtomicBool,
+    // 生きているハンドルの数。0になったらその側をcloseする
+    producers: AtomicUsize,
+    pub(crate) consumers: AtomicUsize,
+    // 同じ側のハンドル同士で奪い合うので、write_idxとread_idxは別のcachelineに置く
+    write_idx: CachePadded<AtomicUsize>,
+    read_idx: CachePadded<AtomicUsize>,
+    pub(crate) wait: W,
+}
+unsafe impl<T: Send, W: WaitStrategy> Sync for Buffer<T, W> {}
+
+/// Cloneable handle, every clone may enqueue from its own thread.
+pub struct Producer<T, W: WaitStrategy = BusySpin> {
+    pub(crate) buffer: Arc<Buffer<T, W>>,
+}
+
+unsafe impl<T: Send, W: WaitStrategy> Send for Producer<T, W> {}
+unsafe impl<T: Send, W: WaitStrategy> Sync for Producer<T, W> {}
+
+impl<T> Buffer<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_wait(capacity, BusySpin)
+    }
+}
+
+impl<T, W: WaitStrategy> Buffer<T, W> {
+    pub fn with_capacity_and_wait(capacity: usize, wait: W) -> Self {
+        // スロットが1つだと読み出し可能(pos+1)と次の周回の書き込み可能が区別できない
+        let len = capacity.next_power_of_two().max(2);
+        let slots = Allocated::<Slot<T>>::heap(len);
+        for i in 0..len {
+            unsafe {
+                ptr::write(
+                    slots.as_ptr().add(i),
+                    Slot {
+                        seq: AtomicUsize::new(i),
+                        value: UnsafeCell::new(MaybeUninit::uninit()),
+                    },
+                );
+            }
+        }
+        Self {
+            slots,
+            capacity,
+            position_mask: len - 1,
+            producer_closed: AtomicBool::new(false),
+            consumer_closed: AtomicBool::new(false),
+            producers: AtomicUsize::new(1),
+            consumers: AtomicUsize::new(1),
+            write_idx: CachePadded::default(),
+            read_idx: CachePadded::default(),
+            wait,
+        }
+    }
+
+    #[inline]
+    fn slot(&self, pos: usize) -> &Slot<T> {
+        unsafe { &*self.slots.as_ptr().add(pos & self.position_mask) }
+    }
+
+    /// Safe to call from many threads at once.
+    pub(crate) fn try_enqueue(&self, item: T) -> Result<(), Full<T>> {
+        let mut pos = self.write_idx.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = self.slot(pos);
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos) as isize;
+            if diff == 0 {
+                // スロット数を2の冪に切り上げているので、要求された容量を超えないか確認する
+                if self.capacity <= self.position_mask
+                    && self.capacity <= pos.wrapping_sub(self.read_idx.load(Ordering::Acquire))
+                {
+                    return Err(Full(item));
+                }
+                match self.write_idx.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break slot,
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // Consumerがまだ前の周回の要素を読んでいない
+                return Err(Full(item));
+            } else {
+                // 他のProducerに先を越された
+                pos = self.write_idx.load(Ordering::Relaxed);
+            }
+        };
+
+        unsafe {
+            (*slot.value.get()).write(item);
+        }
+        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+        self.wait.notify();
+        Ok(())
+    }
+
+    /// Dequeue without a CAS on `read_idx`.
+    ///
+    /// # Safety
+    /// No other thread may dequeue at the same time.
+    pub(crate) unsafe fn try_dequeue_single(&self) -> Result<T, Empty> {
+        let pos = self.read_idx.load(Ordering::Relaxed);
+        let slot = self.slot(pos);
+        if slot.seq.load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return Err(Empty);
+        }
+
+        let v = (*slot.value.get()).assume_init_read();
+        self.release(slot, pos);
+        self.read_idx.store(pos.wrapping_add(1), Ordering::Release);
+        self.wait.notify();
+        Ok(v)
+    }
+
+    /// Safe to call from many threads at once.
+    pub(crate) fn try_dequeue(&self) -> Result<T, Empty> {
+        let mut pos = self.read_idx.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = self.slot(pos);
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos.wrapping_add(1)) as isize;
+            if diff == 0 {
+                match self.read_idx.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break slot,
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // Producerがまだ書き込んでいない
+                return Err(Empty);
+            } else {
+                // 他のConsumerに先を越された
+                pos = self.read_idx.load(Ordering::Relaxed);
+            }
+        };
+
+        let v = unsafe { (*slot.value.get()).assume_init_read() };
+        self.release(slot, pos);
+        self.wait.notify();
+        Ok(v)
+    }
+
+    /// Hands a read slot back to the producers for the next lap.
+    #[inline]
+    fn release(&self, slot: &Slot<T>, pos: usize) {
+        slot.seq
+            .store(pos.wrapping_add(self.position_mask + 1), Ordering::Release);
+    }
+
+    /// `true` when the slot at `read_idx` has been written.
+    #[inline]
+    pub(crate) fn is_readable(&self) -> bool {
+        let pos = self.read_idx.load(Ordering::Relaxed);
+        self.slot(pos).seq.load(Ordering::Acquire) == pos.wrapping_add(1)
+    }
+
+    /// Number of claimed slots, including ones whose producer has not finished writing
+    /// and ones whose consumer has not finished reading.
+    #[inline]
+    pub(crate) fn claimed(&self) -> usize {
+        let read_idx = self.read_idx.load(Ordering::Acquire);
+        self.write_idx
+            .load(Ordering::Acquire)
+            .wrapping_sub(read_idx)
+            .min(self.capacity)
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for Buffer<T, W> {
+    fn drop(&mut self) {
+        // 全てのハンドルがdropされているので書き込み途中のスロットは無い
+        while unsafe { self.try_dequeue_single() }.is_ok() {}
+    }
+}
+
+/// Builds the buffer and hands out the first producer together with the buffer
+/// for the ring's own consumer.
+pub(crate) fn make_with_wait<T, W: WaitStrategy>(
+    capacity: usize,
+    wait: W,
+) -> (Producer<T, W>, Arc<Buffer<T, W>>) {
+    let arc = Arc::new(Buffer::with_capacity_and_wait(capacity, wait));
+    (
+        Producer {
+            buffer: arc.clone(),
+        },
+        arc,
+    )
+}
+
+impl<T, W: WaitStrategy> RingBufProducer<T> for Producer<T, W> {
+    fn try_enqueue(&self, item: T) -> Result<(), TryEnqueueError<T>> {
+        if self.is_closed() {
+            return Err(TryEnqueueError::Disconnected(item));
+        }
+        self.buffer.try_enqueue(item).map_err(Into::into)
+    }
+}
+
+impl<T, W: WaitStrategy> RingBufBlockingProducer<T> for Producer<T, W> {
+    fn is_closed(&self) -> bool {
+        self.buffer.consumer_closed.load(Ordering::Acquire)
+            || self.buffer.producer_closed.load(Ordering::Relaxed)
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.capacity
+    }
+
+    /// Number of free slots. Other producers may take them at any time.
+    fn free_slots(&self) -> usize {
+        self.capacity() - self.len()
+    }
+
+    /// Number of items in the ring, including ones other producers are still writing.
+    fn len(&self) -> usize {
+        self.buffer.claimed()
+    }
+
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool {
+        self.buffer.wait.wait_until(ready, deadline)
+    }
+}
+
+impl<T, W: WaitStrategy> Producer<T, W> {
+    /// Marks the ring as closed for every producer. The consumer side gets `Disconnected`
+    /// once the remaining items are drained. Dropping the last producer closes the ring as well.
+    pub fn close(&self) {
+        self.buffer.producer_closed.store(true, Ordering::Release);
+        self.buffer.wait.notify();
+    }
+}
+
+impl<T, W: WaitStrategy> Clone for Producer<T, W> {
+    fn clone(&self) -> Self {
+        self.buffer.producers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            buffer: self.buffer.clone(),
+        }
+    }
+}
+
+impl<T, W: WaitStrategy> Drop for Producer<T, W> {
+    fn drop(&mut self) {
+        // 最後のProducerがdropされたときだけ閉じる
+        if self.buffer.producers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.close();
+        }
+    }
+}
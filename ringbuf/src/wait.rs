@@ -0,0 +1,392 @@
+//! Strategies to wait for the peer of a ring when it is full or empty.
+//!
+//! A strategy is owned by the ring. The waiting side calls [`WaitStrategy::wait_until`] with a
+//! condition that re-checks the ring, and the other side calls [`WaitStrategy::notify`] after
+//! every publish of its index, so that strategies which put the thread to sleep can wake it.
+
+use std::{
+    hint, mem,
+    sync::{
+        atomic::{fence, AtomicUsize, Ordering},
+        Mutex,
+    },
+    task::Waker,
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+pub trait WaitStrategy: Send + Sync {
+    /// Waits until `ready` returns `true` or `deadline` passes.
+    /// Returns the last result of `ready`, so `false` means the deadline was reached.
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool;
+
+    /// Wakes the peer blocked in [`WaitStrategy::wait_until`].
+    /// Called after every publish of `write_idx`/`read_idx` and when the ring is closed.
+    #[inline]
+    fn notify(&self) {}
+}
+
+#[inline]
+fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|d| d <= Instant::now())
+}
+
+/// Re-checks the condition in a tight loop.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BusySpin;
+
+impl WaitStrategy for BusySpin {
+    fn wait_until<F: FnMut() -> bool>(&self, mut ready: F, deadline: Option<Instant>) -> bool {
+        loop {
+            if ready() {
+                return true;
+            }
+            if expired(deadline) {
+                return false;
+            }
+        }
+    }
+}
+
+/// Re-checks the condition in a loop with `spin_loop` hint (`pause`/`yield` instruction).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SpinLoopHint;
+
+impl WaitStrategy for SpinLoopHint {
+    fn wait_until<F: FnMut() -> bool>(&self, mut ready: F, deadline: Option<Instant>) -> bool {
+        loop {
+            if ready() {
+                return true;
+            }
+            if expired(deadline) {
+                return false;
+            }
+            hint::spin_loop();
+        }
+    }
+}
+
+/// Gives up the time slice to the OS scheduler between checks.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Yield;
+
+impl WaitStrategy for Yield {
+    fn wait_until<F: FnMut() -> bool>(&self, mut ready: F, deadline: Option<Instant>) -> bool {
+        loop {
+            if ready() {
+                return true;
+            }
+            if expired(deadline) {
+                return false;
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+/// Spins with exponentially growing count, then yields, then sleeps up to 1ms per check.
+/// The last step is the same as the 1ms sleep of `RingBuffer4` in `reference/main.cc`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Backoff;
+
+impl Backoff {
+    const SPIN_LIMIT: u32 = 6;
+    const YIELD_LIMIT: u32 = 10;
+    const SLEEP_LIMIT: u32 = Self::YIELD_LIMIT + 10;
+}
+
+impl WaitStrategy for Backoff {
+    fn wait_until<F: FnMut() -> bool>(&self, mut ready: F, deadline: Option<Instant>) -> bool {
+        let mut step = 0;
+        loop {
+            if ready() {
+                return true;
+            }
+            if expired(deadline) {
+                return false;
+            }
+            if step <= Self::SPIN_LIMIT {
+                for _ in 0..(1 << step) {
+                    hint::spin_loop();
+                }
+            } else if step <= Self::YIELD_LIMIT {
+                thread::yield_now();
+            } else {
+                // 1us, 2us, ... 1024us
+                let sleep = Duration::from_micros(1 << (step - Self::YIELD_LIMIT - 1));
+                match deadline {
+                    Some(d) => {
+                        thread::sleep(sleep.min(d.saturating_duration_since(Instant::now())))
+                    }
+                    None => thread::sleep(sleep),
+                }
+            }
+            if step <= Self::SLEEP_LIMIT {
+                step += 1;
+            }
+        }
+    }
+}
+
+/// Parks the waiting thread, the peer unparks it on notify.
+/// `notify` costs a fence and a load while nobody is parked.
+#[derive(Debug, Default)]
+pub struct Park {
+    sleepers: AtomicUsize,
+    threads: Mutex<Vec<Thread>>,
+}
+
+impl WaitStrategy for Park {
+    fn wait_until<F: FnMut() -> bool>(&self, mut ready: F, deadline: Option<Instant>) -> bool {
+        if ready() {
+            return true;
+        }
+        let me = thread::current();
+        self.threads.lock().unwrap().push(me.clone());
+        // notify側の fence と組になり、条件の再確認とsleepersの確認のどちらかが必ず相手の更新を見る
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+
+        let result = loop {
+            if ready() {
+                break true;
+            }
+            match deadline {
+                None => thread::park(),
+                Some(d) => {
+                    let now = Instant::now();
+                    if d <= now {
+                        break false;
+                    }
+                    thread::park_timeout(d - now);
+                }
+            }
+        };
+
+        self.sleepers.fetch_sub(1, Ordering::Relaxed);
+        let mut threads = self.threads.lock().unwrap();
+        if let Some(i) = threads.iter().position(|t| t.id() == me.id()) {
+            threads.swap_remove(i);
+        }
+        result
+    }
+
+    #[inline]
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        for t in self.threads.lock().unwrap().iter() {
+            t.unpark();
+        }
+    }
+}
+
+/// Wakes the async tasks waiting in `send`/`recv` of the r3 handles.
+/// Blocking waits fall back to [`Backoff`].
+/// `notify` costs a fence and a load while no task is registered.
+#[derive(Debug, Default)]
+pub struct AsyncWake {
+    registered: AtomicUsize,
+    // [producer, consumer] のタスクを1つずつ登録する
+    wakers: Mutex<[Option<Waker>; 2]>,
+}
+
+impl AsyncWake {
+    pub(crate) const PRODUCER: usize = 0;
+    pub(crate) const CONSUMER: usize = 1;
+
+    /// Registers the waker of `side` for the next notify.
+    /// The caller must re-check the ring after this to not miss a publish.
+    pub(crate) fn register(&self, side: usize, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        match &mut wakers[side] {
+            Some(w) if w.will_wake(waker) => {}
+            slot => *slot = Some(waker.clone()),
+        }
+        // notify側の fence と組になる。Park と同じ手順
+        self.registered.store(1, Ordering::SeqCst);
+        drop(wakers);
+        fence(Ordering::SeqCst);
+    }
+
+    pub(crate) fn unregister(&self, side: usize) {
+        let mut wakers = self.wakers.lock().unwrap();
+        wakers[side] = None;
+        if wakers.iter().all(Option::is_none) {
+            self.registered.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl WaitStrategy for AsyncWake {
+    fn wait_until<F: FnMut() -> bool>(&self, ready: F, deadline: Option<Instant>) -> bool {
+        Backoff.wait_until(ready, deadline)
+    }
+
+    #[inline]
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.registered.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let wakers = {
+            let mut wakers = self.wakers.lock().unwrap();
+            self.registered.store(0, Ordering::Relaxed);
+            mem::take(&mut *wakers)
+        };
+        // lockの外で起こす。wakeの中で再登録されてもデッドロックしない
+        for w in wakers.into_iter().flatten() {
+            w.wake();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use futex::Futex;
+
+#[cfg(target_os = "linux")]
+mod futex {
+    use std::{
+        ptr,
+        sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use super::WaitStrategy;
+
+    /// Sleeps on a Linux futex, the peer wakes it on notify.
+    /// `notify` costs a fence and a load while nobody is sleeping.
+    #[derive(Debug, Default)]
+    pub struct Futex {
+        seq: AtomicU32,
+        sleepers: AtomicUsize,
+    }
+
+    fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+        let ts = timeout.map(|d| libc::timespec {
+            tv_sec: d.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+            tv_nsec: d.subsec_nanos() as _,
+        });
+        let ts_ptr = ts
+            .as_ref()
+            .map_or(ptr::null(), |ts| ts as *const libc::timespec);
+        // 値が expected でなければ即座に戻る。EINTR, ETIMEDOUT は呼び出し側で再確認するので無視する
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                futex as *const AtomicU32,
+                libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                expected,
+                ts_ptr,
+            );
+        }
+    }
+
+    fn futex_wake_all(futex: &AtomicU32) {
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                futex as *const AtomicU32,
+                libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+                i32::MAX,
+            );
+        }
+    }
+
+    impl WaitStrategy for Futex {
+        fn wait_until<F: FnMut() -> bool>(&self, mut ready: F, deadline: Option<Instant>) -> bool {
+            if ready() {
+                return true;
+            }
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+
+            let result = loop {
+                let seq = self.seq.load(Ordering::Acquire);
+                if ready() {
+                    break true;
+                }
+                let timeout = match deadline {
+                    None => None,
+                    Some(d) => {
+                        let now = Instant::now();
+                        if d <= now {
+                            break false;
+                        }
+                        Some(d - now)
+                    }
+                };
+                futex_wait(&self.seq, seq, timeout);
+            };
+
+            self.sleepers.fetch_sub(1, Ordering::Relaxed);
+            result
+        }
+
+        #[inline]
+        fn notify(&self) {
+            fence(Ordering::SeqCst);
+            if self.sleepers.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            self.seq.fetch_add(1, Ordering::Release);
+            futex_wake_all(&self.seq);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    fn check_wakeup<W: WaitStrategy + 'static>(wait: W) {
+        let wait = Arc::new(wait);
+        let flag = Arc::new(AtomicBool::new(false));
+        let (w, f) = (wait.clone(), flag.clone());
+        let h = thread::spawn(move || w.wait_until(|| f.load(Ordering::Acquire), None));
+        thread::sleep(Duration::from_millis(10));
+        flag.store(true, Ordering::Release);
+        wait.notify();
+        assert!(h.join().unwrap());
+    }
+
+    fn check_deadline<W: WaitStrategy>(wait: W) {
+        let start = Instant::now();
+        let timeout = Duration::from_millis(20);
+        assert!(!wait.wait_until(|| false, Some(start + timeout)));
+        assert!(timeout <= start.elapsed());
+        assert!(wait.wait_until(|| true, Some(start)));
+    }
+
+    #[test]
+    fn test_strategies() {
+        check_wakeup(BusySpin);
+        check_wakeup(SpinLoopHint);
+        check_wakeup(Yield);
+        check_wakeup(Backoff);
+        check_wakeup(Park::default());
+        check_wakeup(AsyncWake::default());
+        #[cfg(target_os = "linux")]
+        check_wakeup(Futex::default());
+
+        check_deadline(BusySpin);
+        check_deadline(SpinLoopHint);
+        check_deadline(Yield);
+        check_deadline(Backoff);
+        check_deadline(Park::default());
+        check_deadline(AsyncWake::default());
+        #[cfg(target_os = "linux")]
+        check_deadline(Futex::default());
+    }
+}
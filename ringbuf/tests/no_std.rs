@@ -0,0 +1,41 @@
+//! Builds the crate without `std` for a bare-metal target.
+//! Fails when the target is missing, run `rustup target add thumbv7em-none-eabihf` first.
+
+use std::{path::Path, process::Command};
+
+const TARGET: &str = "thumbv7em-none-eabihf";
+
+fn target_installed() -> bool {
+    let Ok(out) = Command::new("rustc").args(["--print", "sysroot"]).output() else {
+        return false;
+    };
+    let sysroot = String::from_utf8_lossy(&out.stdout);
+    Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(TARGET)
+        .exists()
+}
+
+#[test]
+fn test_no_std_build() {
+    let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+    // 外側のcargoとbuild lockを取り合わないように別のtarget dirを使う
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("no_std");
+    // hostでビルドするとstdがリンクできてしまい、no_stdの確認にならない
+    assert!(
+        target_installed(),
+        "{TARGET} is not installed, run `rustup target add {TARGET}`"
+    );
+
+    for features in [&[][..], &["--features", "alloc"]] {
+        let mut cmd = Command::new(env!("CARGO"));
+        cmd.args(["build", "--quiet", "--lib", "--no-default-features"])
+            .args(features)
+            .args(["--manifest-path", manifest])
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .args(["--target", TARGET]);
+        let status = cmd.status().unwrap();
+        assert!(status.success(), "no_std build failed with {:?}", features);
+    }
+}
@@ -0,0 +1,49 @@
+//! Producer in a forked child process, consumer in the parent.
+#![cfg(target_os = "linux")]
+
+use ringbuf::{shm::Region, RingBufConsumer, RingBufProducer, TryDequeueError};
+
+#[test]
+fn test_fork_producer() {
+    let count = 100_000_u64;
+    let name = format!("/ringbuf-test-fork-{}", std::process::id());
+    let consumer = Region::<[u64; 2]>::create(&name, 64)
+        .unwrap()
+        .into_consumer();
+
+    let pid = unsafe { libc::fork() };
+    assert!(0 <= pid, "fork failed");
+    if pid == 0 {
+        // 子プロセスは名前で開き直し、デストラクタを走らせずに終了する
+        let code = match Region::<[u64; 2]>::open(&name) {
+            Ok(region) => {
+                let producer = region.into_producer();
+                for i in 0..count {
+                    while !producer.enqueue([i, !i]) {
+                        std::thread::yield_now();
+                    }
+                }
+                producer.close();
+                0
+            }
+            Err(_) => 1,
+        };
+        unsafe { libc::_exit(code) };
+    }
+
+    let mut next = 0;
+    loop {
+        match consumer.try_dequeue() {
+            Ok([v, inv]) => {
+                assert_eq!((v, inv), (next, !next));
+                next += 1;
+            }
+            Err(TryDequeueError::Empty) => std::thread::yield_now(),
+            Err(TryDequeueError::Disconnected) => break,
+        }
+    }
+    let mut status = 0;
+    unsafe { libc::waitpid(pid, &mut status, 0) };
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    assert_eq!(next, count);
+}